@@ -17,6 +17,11 @@ pub struct Opts {
         help = "Boud rate of the serial device"
     )]
     pub boud_rate: u32,
+    #[clap(
+        long,
+        help = "URL of the bus, rtu://<serial path> or tcp://<host>:<port>, used instead of the serial device"
+    )]
+    pub bus: Option<String>,
     #[clap(subcommand)]
     pub subcmd: SubCommand,
 }
@@ -1,12 +1,8 @@
-use anyhow::{bail, Context};
+use anyhow::bail;
 use clap::Parser;
 use cli::{ReadVersion, SetDeviceAddress, SubCommand};
-use dorfbusext::DorfbusExt;
-use tokio_modbus::{
-    client::{rtu, Context as RtuContext},
-    prelude::{Slave, SlaveContext},
-};
-use tokio_serial::SerialStream;
+use dorfbusext::{DorfbusBus, DorfbusExt, Transport};
+use tokio_modbus::prelude::Slave;
 
 use crate::cli::Opts;
 
@@ -15,10 +11,14 @@ mod cli;
 async fn run() -> anyhow::Result<()> {
     let opts: Opts = Opts::parse();
 
-    let builder = tokio_serial::new(opts.serial_device, opts.boud_rate);
-    let port = SerialStream::open(&builder).with_context(|| "Could not open the serial device")?;
-
-    let modbus_ctx = rtu::connect(port).await?;
+    let transport = match &opts.bus {
+        Some(url) => Transport::parse(url, opts.boud_rate)?,
+        None => Transport::Rtu {
+            path: opts.serial_device,
+            boud: opts.boud_rate,
+        },
+    };
+    let modbus_ctx = transport.connect().await?;
 
     match opts.subcmd {
         SubCommand::ReadVersion(params) => read_version(modbus_ctx, &params).await?,
@@ -28,8 +28,11 @@ async fn run() -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn read_version(mut modbus_ctx: RtuContext, params: &ReadVersion) -> anyhow::Result<()> {
-    modbus_ctx.set_slave(Slave(params.modbus_id));
+/// A bus with relais cards, independent of the transport
+type Bus = Box<dyn DorfbusBus>;
+
+async fn read_version(mut modbus_ctx: Bus, params: &ReadVersion) -> anyhow::Result<()> {
+    modbus_ctx.select_slave(Slave(params.modbus_id));
 
     //let month = modbus_ctx.read_holding_registers(0x04, 1).await?;
     //eprintln!("read month {:?}", month);
@@ -50,10 +53,7 @@ async fn read_version(mut modbus_ctx: RtuContext, params: &ReadVersion) -> anyho
     Ok(())
 }
 
-async fn set_device_address(
-    mut modbus_ctx: RtuContext,
-    params: &SetDeviceAddress,
-) -> anyhow::Result<()> {
+async fn set_device_address(mut modbus_ctx: Bus, params: &SetDeviceAddress) -> anyhow::Result<()> {
     let slave = Slave(params.modbus_id);
 
     if slave.is_broadcast() {
@@ -68,7 +68,7 @@ async fn set_device_address(
         bail!("{} is not a valid device address!", params.modbus_id);
     }
 
-    modbus_ctx.set_slave(Slave::broadcast());
+    modbus_ctx.select_slave(Slave::broadcast());
     modbus_ctx.set_device_address(params.modbus_id).await?;
 
     println!(
@@ -76,7 +76,7 @@ async fn set_device_address(
         params.modbus_id
     );
 
-    modbus_ctx.set_slave(slave);
+    modbus_ctx.select_slave(slave);
     let version = modbus_ctx.read_hardware_version().await?;
 
     println!(
@@ -1,9 +1,9 @@
-use std::time::Duration;
+use std::{future::Future, time::Duration};
 
 use axum::{
-    extract::{Extension, Path},
+    extract::{Extension, Path, Query},
     response::IntoResponse,
-    routing::get,
+    routing::{get, post},
     Json, Router,
 };
 use dorfbusext::DorfbusExt;
@@ -14,10 +14,12 @@ use serde::{Deserialize, Serialize};
 use serde_json::json;
 use thiserror::Error;
 use tokio::time::{error::Elapsed, timeout};
-use tokio_modbus::prelude::{Slave, SlaveContext};
-use tracing::{info, instrument};
+use tokio_modbus::prelude::Slave;
+use tracing::instrument;
 
 use crate::{
+    bus_queue::Priority,
+    config::DEFAULT_BUS,
     state::{State, StateError, StateResult},
     swagger_ui::swagger_routes,
 };
@@ -26,6 +28,10 @@ use crate::{
 pub enum ApiError {
     #[error("Modbus timed out")]
     ModbusTimeout,
+    #[error("request did not finish within {0:?}")]
+    Deadline(Duration),
+    #[error(transparent)]
+    State(#[from] StateError),
 }
 
 impl From<Elapsed> for ApiError {
@@ -36,10 +42,15 @@ impl From<Elapsed> for ApiError {
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> http::Response<axum::body::BoxBody> {
+        let (status, short) = match self {
+            ApiError::State(err) => return err.into_response(),
+            ApiError::Deadline(_) => (StatusCode::GATEWAY_TIMEOUT, "deadline_exceeded"),
+            ApiError::ModbusTimeout => (StatusCode::INTERNAL_SERVER_ERROR, "todo"),
+        };
         (
-            StatusCode::INTERNAL_SERVER_ERROR,
+            status,
             Json(ApiErrorResponse {
-                short: "todo".into(),
+                short: short.into(),
                 message: self.to_string(),
             }),
         )
@@ -49,10 +60,15 @@ impl IntoResponse for ApiError {
 
 impl IntoResponse for StateError {
     fn into_response(self) -> http::Response<axum::body::BoxBody> {
+        let (status, short) = match self {
+            StateError::DeviceOffline(_) => (StatusCode::SERVICE_UNAVAILABLE, "device_offline"),
+            StateError::Interlocked { .. } => (StatusCode::CONFLICT, "interlocked"),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, "todo"),
+        };
         (
-            StatusCode::INTERNAL_SERVER_ERROR,
+            status,
             Json(ApiErrorResponse {
-                short: "todo".into(),
+                short: short.into(),
                 message: self.to_string(),
             }),
         )
@@ -80,6 +96,19 @@ fn example_error_message() -> &'static str {
 
 pub type ApiResult<T> = Result<T, ApiError>;
 
+/// Run a request which accesses the bus within the request deadline
+///
+/// Bus commands which were already queued are still sent after the deadline.
+async fn with_deadline<T>(
+    state: &State,
+    request: impl Future<Output = ApiResult<T>>,
+) -> ApiResult<T> {
+    let deadline = state.params().request_timeout;
+    timeout(deadline, request)
+        .await
+        .map_err(|_| ApiError::Deadline(deadline))?
+}
+
 async fn openapi_json(Extension(state): Extension<State>) -> impl IntoResponse {
     let mut spec: openapiv3::OpenAPI =
         serde_yaml::from_str(include_str!("openapi.yml")).expect("could not parse openapi spec");
@@ -95,7 +124,7 @@ async fn openapi_json(Extension(state): Extension<State>) -> impl IntoResponse {
 
 #[instrument(skip_all)]
 async fn config(Extension(state): Extension<State>) -> impl IntoResponse {
-    Json(state.config().clone())
+    Json(state.config().as_ref().clone())
 }
 
 #[instrument(skip_all)]
@@ -103,38 +132,158 @@ async fn state(Extension(state): Extension<State>) -> impl IntoResponse {
     Json(state.bus_state())
 }
 
+#[instrument(skip_all)]
+async fn buses(Extension(state): Extension<State>) -> impl IntoResponse {
+    Json(state.bus_statuses())
+}
+
+/// Query parameters of a hardware version request
+#[derive(Debug, Deserialize)]
+pub struct HardwareVersionQuery {
+    /// Name of the bus the device is connected to
+    #[serde(default = "default_bus")]
+    pub bus: String,
+}
+
+fn default_bus() -> String {
+    DEFAULT_BUS.to_owned()
+}
+
 #[instrument(skip(state))]
 async fn device_hardware_id(
     Path(device_id): Path<u8>,
+    Query(query): Query<HardwareVersionQuery>,
     Extension(state): Extension<State>,
 ) -> ApiResult<impl IntoResponse> {
-    info!("locking modbus device...");
-    let mut modbus = state.modbus().lock().await;
+    // use the timeout of the configured device with this address, if there is one
+    let modbus_timeout = state
+        .bus_state()
+        .devices
+        .values()
+        .find(|device| device.config.bus == query.bus && device.config.modbus_address == device_id)
+        .map(|device| device.timeout)
+        .unwrap_or_else(|| state.config().bus_timeout(&query.bus));
+
+    with_deadline(&state, async {
+        let read = state
+            .bus_queue(&query.bus)?
+            .exclusive(Priority::Normal, move |modbus| {
+                Box::pin(async move {
+                    modbus.select_slave(Slave(device_id));
+                    timeout(modbus_timeout, modbus.read_hardware_version()).await
+                })
+            });
+        let hardware_version = read
+            .await
+            .map_err(StateError::from)??
+            .map_err(StateError::from)?;
+        Ok(Json(json!({ "hardware-version": hardware_version })))
+    })
+    .await
+}
 
-    modbus.set_slave(Slave(device_id));
-    let hardware_version_res =
-        timeout(Duration::from_secs(1), modbus.read_hardware_version()).await?;
-    let hardware_version = hardware_version_res.unwrap();
-    Ok(Json(json!({ "hardware-version": hardware_version })))
+/// Query parameters of a coil status request
+#[derive(Debug, Deserialize)]
+pub struct GetCoilQuery {
+    /// Read the status from the device instead of using the cached value
+    #[serde(default)]
+    pub refresh: bool,
 }
 
 #[instrument(skip(state))]
 async fn get_coil(
     Path(name): Path<String>,
+    Query(query): Query<GetCoilQuery>,
     Extension(state): Extension<State>,
-) -> StateResult<impl IntoResponse> {
-    let coil_update = state.get_coil(&name).await?;
+) -> ApiResult<impl IntoResponse> {
+    let coil_update = with_deadline(&state, async {
+        Ok(state.get_coil(&name, query.refresh).await?)
+    })
+    .await?;
 
     Ok(Json(coil_update))
 }
 
+/// Query parameters of requests which write to the bus
+#[derive(Debug, Deserialize)]
+pub struct SetQuery {
+    /// Priority of the bus commands
+    #[serde(default)]
+    pub priority: Priority,
+}
+
+/// Query parameters of a tag update
+#[derive(Debug, Deserialize)]
+pub struct SetTagQuery {
+    /// Priority of the bus commands
+    #[serde(default)]
+    pub priority: Priority,
+    /// Run the update in the background and return a job
+    #[serde(default, rename = "async")]
+    pub run_async: bool,
+}
+
+/// Query parameters of a coil update
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SetCoilQuery {
+    /// Switch the coil off automatically after this many milliseconds, if it is switched on
+    pub duration_ms: Option<u64>,
+    /// Priority of the bus commands
+    #[serde(default)]
+    pub priority: Priority,
+}
+
 #[instrument(skip(state))]
 async fn set_coil(
     Json(enabled): Json<bool>,
     Path(name): Path<String>,
+    Query(query): Query<SetCoilQuery>,
     Extension(state): Extension<State>,
-) -> StateResult<impl IntoResponse> {
-    let coil_update = state.set_coil(&name, enabled).await?;
+) -> ApiResult<impl IntoResponse> {
+    let duration = query.duration_ms.map(Duration::from_millis);
+    let coil_update = with_deadline(&state, async {
+        Ok(state
+            .set_coil(&name, enabled, duration, query.priority)
+            .await?)
+    })
+    .await?;
+
+    Ok(Json(coil_update))
+}
+
+/// Query parameters of a pulse request
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PulseQuery {
+    /// Duration of the pulse in milliseconds, the configured duration of the coil if not given
+    pub duration_ms: Option<u64>,
+    /// Priority of the bus commands
+    #[serde(default)]
+    pub priority: Priority,
+}
+
+#[instrument(skip(state))]
+async fn pulse_coil(
+    Path(name): Path<String>,
+    Query(query): Query<PulseQuery>,
+    Extension(state): Extension<State>,
+) -> ApiResult<impl IntoResponse> {
+    let duration = query.duration_ms.map(Duration::from_millis);
+    let coil_update = with_deadline(&state, async {
+        Ok(state.pulse_coil(&name, duration, query.priority).await?)
+    })
+    .await?;
+
+    Ok(Json(coil_update))
+}
+
+#[instrument(skip(state))]
+async fn toggle_coil(
+    Path(name): Path<String>,
+    Extension(state): Extension<State>,
+) -> ApiResult<impl IntoResponse> {
+    let coil_update = with_deadline(&state, async { Ok(state.toggle_coil(&name).await?) }).await?;
 
     Ok(Json(coil_update))
 }
@@ -152,10 +301,99 @@ async fn get_tag(
 #[instrument(skip(state))]
 async fn set_tag(
     Json(enabled): Json<bool>,
+    Path(name): Path<String>,
+    Query(query): Query<SetTagQuery>,
+    Extension(state): Extension<State>,
+) -> ApiResult<http::Response<axum::body::BoxBody>> {
+    if query.run_async {
+        state.get_tag(&name).await?;
+        let job_state = state.clone();
+        let job =
+            state.spawn_job(async move { job_state.set_tag(&name, enabled, query.priority).await });
+        return Ok((StatusCode::ACCEPTED, Json(job)).into_response());
+    }
+
+    let coil_updates = with_deadline(&state, async {
+        Ok(state.set_tag(&name, enabled, query.priority).await?)
+    })
+    .await?;
+
+    Ok(Json(coil_updates).into_response())
+}
+
+#[instrument(skip(state))]
+async fn toggle_tag(
+    Path(name): Path<String>,
+    Query(query): Query<SetTagQuery>,
+    Extension(state): Extension<State>,
+) -> ApiResult<http::Response<axum::body::BoxBody>> {
+    if query.run_async {
+        state.get_tag(&name).await?;
+        let job_state = state.clone();
+        let job = state.spawn_job(async move { job_state.toggle_tag(&name, query.priority).await });
+        return Ok((StatusCode::ACCEPTED, Json(job)).into_response());
+    }
+
+    let coil_updates = with_deadline(&state, async {
+        Ok(state.toggle_tag(&name, query.priority).await?)
+    })
+    .await?;
+
+    Ok(Json(coil_updates).into_response())
+}
+
+#[instrument(skip(state))]
+async fn job(
+    Path(id): Path<u64>,
+    Extension(state): Extension<State>,
+) -> StateResult<impl IntoResponse> {
+    Ok(Json(state.job_status(id)?))
+}
+
+#[instrument(skip_all)]
+async fn schedules(Extension(state): Extension<State>) -> impl IntoResponse {
+    Json(state.schedule_statuses())
+}
+
+#[instrument(skip(state))]
+async fn pause_schedule(
     Path(name): Path<String>,
     Extension(state): Extension<State>,
 ) -> StateResult<impl IntoResponse> {
-    let coil_updates = state.set_tag(&name, enabled).await?;
+    Ok(Json(state.pause_schedule(&name, true).await?))
+}
+
+#[instrument(skip(state))]
+async fn resume_schedule(
+    Path(name): Path<String>,
+    Extension(state): Extension<State>,
+) -> StateResult<impl IntoResponse> {
+    Ok(Json(state.pause_schedule(&name, false).await?))
+}
+
+#[instrument(skip_all)]
+async fn scenes(Extension(state): Extension<State>) -> impl IntoResponse {
+    Json(state.scene_statuses())
+}
+
+#[instrument(skip(state))]
+async fn get_scene(
+    Path(name): Path<String>,
+    Extension(state): Extension<State>,
+) -> StateResult<impl IntoResponse> {
+    Ok(Json(state.scene_status(&name)?))
+}
+
+#[instrument(skip(state))]
+async fn activate_scene(
+    Path(name): Path<String>,
+    Query(query): Query<SetQuery>,
+    Extension(state): Extension<State>,
+) -> ApiResult<impl IntoResponse> {
+    let coil_updates = with_deadline(&state, async {
+        Ok(state.activate_scene(&name, query.priority).await?)
+    })
+    .await?;
 
     Ok(Json(coil_updates))
 }
@@ -164,12 +402,22 @@ fn api_v1_routes() -> Router {
     Router::new()
         .route("/config", get(config))
         .route("/state", get(state))
+        .route("/buses", get(buses))
         .route(
             "/device-hardware-version/:device-id",
             get(device_hardware_id),
         )
         .route("/coil/:name", get(get_coil).post(set_coil))
+        .route("/coil/:name/pulse", post(pulse_coil))
+        .route("/coil/:name/toggle", post(toggle_coil))
         .route("/tag/:name", get(get_tag).post(set_tag))
+        .route("/tag/:name/toggle", post(toggle_tag))
+        .route("/job/:id", get(job))
+        .route("/schedules", get(schedules))
+        .route("/schedule/:name/pause", post(pause_schedule))
+        .route("/schedule/:name/resume", post(resume_schedule))
+        .route("/scenes", get(scenes))
+        .route("/scene/:name", get(get_scene).post(activate_scene))
 }
 
 pub fn api_routes() -> Router {
@@ -0,0 +1,85 @@
+use std::time::{Duration, SystemTime};
+
+use tokio::time::{self, MissedTickBehavior};
+use tracing::{info, instrument, warn};
+
+use crate::{bus_queue::Priority, state::State};
+
+/// Interval in which the auto-off timers are checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Delay before switching off a coil is tried again
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Switch off the coils whose auto-off timer expired
+///
+/// The timers are restored from the state file,
+/// so timers which expired while dorfbusd was not running are handled on startup.
+/// Timers which started because a coil was seen on are written to the state file here.
+#[instrument(skip_all)]
+pub async fn run_auto_off(state: State) {
+    let mut interval = time::interval(CHECK_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        interval.tick().await;
+        let now = SystemTime::now();
+
+        for coil_state in state.bus_state().coils.values() {
+            let mut auto_off = coil_state.auto_off.write().unwrap();
+            if !matches!(*auto_off, Some(off_at) if off_at <= now) {
+                continue;
+            }
+            // switching the coil off cancels the timer, otherwise it is tried again
+            *auto_off = Some(now + RETRY_DELAY);
+            tokio::spawn(switch_off(state.clone(), coil_state.name.clone()));
+        }
+
+        if let Some(store) = state.state_store() {
+            let bus_state = state.bus_state();
+            let timers: Vec<_> = bus_state
+                .coils
+                .values()
+                .map(|coil_state| {
+                    (
+                        coil_state.name.as_str(),
+                        *coil_state.auto_off.read().unwrap(),
+                    )
+                })
+                .collect();
+            store.set_auto_offs(&timers).await;
+        }
+    }
+}
+
+async fn switch_off(state: State, name: String) {
+    info!(event = "auto-off", name = %name, "switch off coil, its timer expired");
+    if let Err(err) = state.set_coil(&name, false, None, Priority::High).await {
+        warn!(name = %name, %err, "could not switch off coil, trying again");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::switch_off;
+    use crate::{bus_state::CircuitBreakerState, config::Config, simulation::simulated_state};
+
+    #[tokio::test]
+    async fn failed_switch_off_keeps_the_timer() {
+        let config: Config = toml::from_str(include_str!("../example-config.toml")).unwrap();
+        let state = simulated_state(config);
+        let coil = state.bus_state().coils["relay-1"].clone();
+        let off_at = SystemTime::now();
+        *coil.auto_off.write().unwrap() = Some(off_at);
+
+        *coil.device.circuit_breaker.write().unwrap() = CircuitBreakerState::Open;
+        switch_off(state.clone(), "relay-1".to_owned()).await;
+        assert_eq!(*coil.auto_off.read().unwrap(), Some(off_at));
+
+        *coil.device.circuit_breaker.write().unwrap() = CircuitBreakerState::Closed;
+        switch_off(state.clone(), "relay-1".to_owned()).await;
+        assert_eq!(*coil.auto_off.read().unwrap(), None);
+    }
+}
@@ -0,0 +1,402 @@
+use std::{
+    cmp::Reverse,
+    collections::{btree_map::Entry, BTreeMap},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{oneshot, Notify};
+use tracing::{debug, info, instrument, warn};
+
+use crate::{
+    bus_state::{CoilState, CoilUpdate, DeviceState},
+    state::{Bus, State, StateResult},
+};
+
+/// Priority of a bus command
+///
+/// Commands with a higher priority are sent to the bus first.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, JsonSchema,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+    Emergency,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+/// A pending write of a coil
+///
+/// Several writes of the same coil are coalesced into a single write of the last value.
+struct PendingWrite {
+    coil: CoilState,
+    value: bool,
+    priority: Priority,
+    /// Position in the queue, the first write of a coil keeps its position
+    sequence: u64,
+    responders: Vec<oneshot::Sender<StateResult<CoilUpdate>>>,
+}
+
+/// A future of a bus operation, which borrows the bus
+pub type BusFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// An operation which uses the bus on its own, e.g. reading a device
+type BusOperation = Box<dyn for<'a> FnOnce(&'a mut Bus) -> BusFuture<'a, ()> + Send>;
+
+/// A pending operation, see [`BusQueue::exclusive`]
+struct PendingOperation {
+    operation: BusOperation,
+    priority: Priority,
+    sequence: u64,
+}
+
+#[derive(Default)]
+struct PendingCommands {
+    next_sequence: u64,
+    writes: BTreeMap<String, PendingWrite>,
+    operations: Vec<PendingOperation>,
+}
+
+/// The next command of the queue
+enum Command {
+    /// Coil writes of a single device
+    Writes(Vec<PendingWrite>),
+    Operation(BusOperation),
+}
+
+/// Command queue of a bus
+///
+/// All commands of a bus are sent by a single worker, see [`BusQueue::run`].
+#[derive(Default)]
+pub struct BusQueue {
+    pending: Mutex<PendingCommands>,
+    notify: Notify,
+}
+
+/// Status of a bus
+#[derive(Serialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub struct BusStatus {
+    /// Number of commands which wait to be sent to the bus
+    pub queue_depth: usize,
+}
+
+impl BusQueue {
+    /// Queue the write of a coil
+    ///
+    /// If a write of the coil is already pending, only the new value will be written
+    /// and all callers receive the result of that write.
+    pub fn write_coil(
+        &self,
+        coil: &CoilState,
+        value: bool,
+        priority: Priority,
+    ) -> oneshot::Receiver<StateResult<CoilUpdate>> {
+        let (tx, rx) = oneshot::channel();
+
+        {
+            let mut pending = self.pending.lock().unwrap();
+            let sequence = pending.next_sequence;
+            pending.next_sequence += 1;
+
+            match pending.writes.entry(coil.name.clone()) {
+                Entry::Occupied(mut entry) => {
+                    let write = entry.get_mut();
+                    debug!(name = %coil.name, old_value = write.value, value, "coalesce coil write");
+                    write.value = value;
+                    write.priority = write.priority.max(priority);
+                    write.responders.push(tx);
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(PendingWrite {
+                        coil: coil.clone(),
+                        value,
+                        priority,
+                        sequence,
+                        responders: vec![tx],
+                    });
+                }
+            }
+        }
+
+        self.notify.notify_one();
+        rx
+    }
+
+    /// Queue an operation which uses the bus on its own, e.g. reading a device
+    ///
+    /// The operation is run by the worker of the bus, ordered by its priority like a coil write.
+    /// No other command is sent to the bus while the operation runs.
+    pub fn exclusive<T, F>(&self, priority: Priority, operation: F) -> oneshot::Receiver<T>
+    where
+        T: Send + 'static,
+        F: for<'a> FnOnce(&'a mut Bus) -> BusFuture<'a, T> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let operation: BusOperation = Box::new(move |bus| {
+            Box::pin(async move {
+                let _tx_result = tx.send(operation(bus).await);
+            })
+        });
+
+        {
+            let mut pending = self.pending.lock().unwrap();
+            let sequence = pending.next_sequence;
+            pending.next_sequence += 1;
+            pending.operations.push(PendingOperation {
+                operation,
+                priority,
+                sequence,
+            });
+        }
+
+        self.notify.notify_one();
+        rx
+    }
+
+    /// Queue reading the hardware version of a device, see [`DeviceState::check_state_from_device`]
+    pub fn check_device(
+        &self,
+        device: &Arc<DeviceState>,
+        priority: Priority,
+    ) -> oneshot::Receiver<StateResult<()>> {
+        let device = device.clone();
+        self.exclusive(priority, move |bus| {
+            Box::pin(async move { device.check_state_from_device(bus).await })
+        })
+    }
+
+    /// Queue reading the states of coils of a device, see [`DeviceState::read_coils_from_device`]
+    ///
+    /// All coils must belong to `device`.
+    pub fn read_coils(
+        &self,
+        device: &Arc<DeviceState>,
+        coils: &[&CoilState],
+        priority: Priority,
+    ) -> oneshot::Receiver<StateResult<u32>> {
+        let device = device.clone();
+        let coils: Vec<CoilState> = coils.iter().map(|coil| (*coil).clone()).collect();
+        self.exclusive(priority, move |bus| {
+            Box::pin(async move {
+                let coils: Vec<&CoilState> = coils.iter().collect();
+                device.read_coils_from_device(bus, &coils).await
+            })
+        })
+    }
+
+    /// Number of pending commands
+    pub fn depth(&self) -> usize {
+        let pending = self.pending.lock().unwrap();
+        pending.writes.len() + pending.operations.len()
+    }
+
+    pub fn status(&self) -> BusStatus {
+        BusStatus {
+            queue_depth: self.depth(),
+        }
+    }
+
+    /// Take the pending command with the highest priority, the oldest first
+    fn pop(&self) -> Option<Command> {
+        let mut pending = self.pending.lock().unwrap();
+        let next_write = pending
+            .writes
+            .values()
+            .map(|write| (write.priority, Reverse(write.sequence)))
+            .max();
+        let next_operation = pending
+            .operations
+            .iter()
+            .enumerate()
+            .map(|(idx, operation)| ((operation.priority, Reverse(operation.sequence)), idx))
+            .max();
+
+        match (next_write, next_operation) {
+            (next_write, Some((next, idx))) if next_write.map_or(true, |write| next > write) => {
+                Some(Command::Operation(pending.operations.remove(idx).operation))
+            }
+            (Some(_), _) => Some(Command::Writes(Self::take_batch(&mut pending))),
+            (None, _) => None,
+        }
+    }
+
+    /// Take the pending write with the highest priority, the oldest first,
+    /// together with all other pending writes of the same device
+    fn take_batch(pending: &mut PendingCommands) -> Vec<PendingWrite> {
+        let device = match pending
+            .writes
+            .values()
+            .max_by_key(|write| (write.priority, Reverse(write.sequence)))
+        {
+            Some(write) => write.coil.device.name.clone(),
+            None => return Vec::new(),
+        };
+
+        let names: Vec<_> = pending
+            .writes
+            .values()
+            .filter(|write| write.coil.device.name == device)
+            .map(|write| write.coil.name.clone())
+            .collect();
+        let mut batch: Vec<_> = names
+            .iter()
+            .filter_map(|name| pending.writes.remove(name))
+            .collect();
+        batch.sort_by_key(|write| (Reverse(write.priority), write.sequence));
+        batch
+    }
+
+    /// Send the queued commands to the bus
+    ///
+    /// The pending writes of a device are sent together,
+    /// so the coils can be written with a single request.
+    /// This runs forever and should be spawned once per bus.
+    #[instrument(skip_all, fields(bus = %bus_name))]
+    pub async fn run(self: Arc<Self>, bus_name: String, state: State) {
+        let bus = match state.bus(&bus_name) {
+            Ok(bus) => bus.clone(),
+            Err(err) => {
+                warn!(%err, "no bus for the command queue");
+                return;
+            }
+        };
+
+        loop {
+            while let Some(command) = self.pop() {
+                let batch = match command {
+                    Command::Writes(batch) => batch,
+                    Command::Operation(operation) => {
+                        let mut modbus_context = bus.lock().await;
+                        operation(&mut modbus_context).await;
+                        continue;
+                    }
+                };
+                let device = match batch.first() {
+                    Some(write) => write.coil.device.clone(),
+                    None => continue,
+                };
+                let bus_state = state.bus_state();
+                let device_coils = bus_state.device_coils(&device.name);
+                let writes: Vec<_> = batch
+                    .iter()
+                    .map(|write| (&write.coil, write.value))
+                    .collect();
+
+                let mut modbus_context = bus.lock().await;
+                for write in &batch {
+                    info!(value = write.value, name = ?write.coil.name, priority = ?write.priority, "set coil");
+                }
+                let results = device
+                    .write_coils(&mut modbus_context, &writes, &device_coils)
+                    .await;
+                drop(modbus_context);
+
+                for (write, result) in batch.into_iter().zip(results) {
+                    let result = result.map(|retries| write.coil.as_update().with_retries(retries));
+                    for responder in write.responders {
+                        let _tx_result = responder.send(result.clone());
+                    }
+                }
+            }
+
+            self.notify.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{BusQueue, Command, PendingWrite, Priority};
+    use crate::bus_state::{CoilState, DeviceState};
+
+    fn coil(name: &str, device: &str) -> CoilState {
+        CoilState {
+            name: name.to_owned(),
+            device: Arc::new(DeviceState {
+                name: device.to_owned(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn pop_batch(queue: &BusQueue) -> Vec<PendingWrite> {
+        match queue.pop() {
+            Some(Command::Writes(batch)) => batch,
+            Some(Command::Operation(_)) => panic!("expected coil writes"),
+            None => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn coalesce_writes_of_the_same_coil() {
+        let queue = BusQueue::default();
+        let _first = queue.write_coil(&coil("a", "x"), true, Priority::Normal);
+        let _second = queue.write_coil(&coil("b", "y"), true, Priority::Normal);
+        let _third = queue.write_coil(&coil("a", "x"), false, Priority::Normal);
+        assert_eq!(queue.depth(), 2);
+
+        let batch = pop_batch(&queue);
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].coil.name, "a");
+        assert!(!batch[0].value);
+        assert_eq!(batch[0].responders.len(), 2);
+    }
+
+    #[test]
+    fn higher_priority_first() {
+        let queue = BusQueue::default();
+        let _low = queue.write_coil(&coil("a", "w"), true, Priority::Low);
+        let _normal = queue.write_coil(&coil("b", "x"), true, Priority::Normal);
+        let _emergency = queue.write_coil(&coil("c", "y"), false, Priority::Emergency);
+        let _normal = queue.write_coil(&coil("d", "z"), true, Priority::Normal);
+
+        let order: Vec<_> = std::iter::from_fn(|| pop_batch(&queue).pop())
+            .map(|write| write.coil.name)
+            .collect();
+        assert_eq!(order, vec!["c", "b", "d", "a"]);
+    }
+
+    #[test]
+    fn batch_writes_of_the_same_device() {
+        let queue = BusQueue::default();
+        let _a = queue.write_coil(&coil("a", "x"), true, Priority::Normal);
+        let _b = queue.write_coil(&coil("b", "y"), true, Priority::Normal);
+        let _c = queue.write_coil(&coil("c", "x"), true, Priority::Low);
+
+        let names: Vec<_> = pop_batch(&queue)
+            .into_iter()
+            .map(|write| write.coil.name)
+            .collect();
+        assert_eq!(names, vec!["a", "c"]);
+        assert_eq!(queue.depth(), 1);
+    }
+
+    #[test]
+    fn operations_are_ordered_with_writes() {
+        let queue = BusQueue::default();
+        let _low = queue.write_coil(&coil("a", "x"), true, Priority::Low);
+        let _read = queue.exclusive(Priority::Normal, |_| Box::pin(async {}));
+        let _normal = queue.write_coil(&coil("b", "y"), true, Priority::Normal);
+        assert_eq!(queue.depth(), 3);
+
+        assert!(matches!(queue.pop(), Some(Command::Operation(_))));
+        assert_eq!(pop_batch(&queue)[0].coil.name, "b");
+        assert_eq!(pop_batch(&queue)[0].coil.name, "a");
+        assert!(queue.pop().is_none());
+    }
+}
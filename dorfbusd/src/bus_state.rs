@@ -1,25 +1,23 @@
 use crate::{
-    config::{self, Config},
-    state::{State, StateError, StateResult},
+    bus_queue::{BusQueue, Priority},
+    config::{self, CoilMode, Config, ResetCoilStatus, DEFAULT_BUS},
+    retry::{modbus_result, Retry},
+    state::{Bus, State, StateError, StateResult},
 };
 use dorfbusext::DorfbusExt;
 pub use schemars::JsonSchema;
-use serde::Serialize;
+use serde::{Serialize, Serializer};
 use std::{
     collections::BTreeMap,
     sync::{
-        atomic::{self, AtomicBool},
+        atomic::{self, AtomicBool, AtomicU32, AtomicU64},
         Arc, RwLock,
     },
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tokio::{sync::oneshot, time::timeout};
-use tokio_modbus::{
-    client::Context as ModbusContext,
-    client::Writer,
-    prelude::{Slave, SlaveContext},
-};
-use tracing::{info, warn};
+use tokio::time::timeout;
+use tokio_modbus::prelude::Slave;
+use tracing::{debug, info, warn};
 
 #[derive(Serialize, Debug, Default, JsonSchema)]
 #[serde(rename_all = "kebab-case")]
@@ -41,12 +39,59 @@ pub struct DeviceState {
     pub version: RwLock<Option<u16>>,
     #[serde(default)]
     pub seen: AtomicBool,
+    /// Unix timestamp of the last response of the device
+    #[serde(default)]
+    pub last_seen: RwLock<Option<u64>>,
+    /// Number of retried Modbus operations of the device
+    #[serde(default)]
+    pub retries: AtomicU64,
+    /// State of the circuit breaker of the device
+    #[serde(default)]
+    pub circuit_breaker: RwLock<CircuitBreakerState>,
+    /// Number of consecutive failed Modbus operations of the device
+    #[serde(default)]
+    pub consecutive_failures: AtomicU32,
+    /// Number of consecutive failures after which the circuit breaker opens, 0 if disabled
+    #[serde(skip)]
+    pub failure_threshold: u32,
+    /// The device rejected writing multiple coils with a single request
+    #[serde(skip)]
+    pub single_writes_only: AtomicBool,
+    /// Retry policy of the Modbus operations of the device
+    #[serde(skip)]
+    pub retry_policy: config::RetryPolicy,
+    /// Timeout of a single Modbus request to the device
+    #[serde(skip)]
+    pub timeout: Duration,
+}
+
+/// State of the circuit breaker of a device
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum CircuitBreakerState {
+    /// The device is online and requests are sent to it
+    Closed,
+    /// The device is offline and requests fail immediately
+    Open,
+}
+
+impl Default for CircuitBreakerState {
+    fn default() -> Self {
+        CircuitBreakerState::Closed
+    }
 }
 
 fn example_106() -> RwLock<Option<u16>> {
     Some(106).into()
 }
 
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
 impl DeviceState {
     /// Reset the state of a device
     pub fn reset(&self) {
@@ -54,28 +99,297 @@ impl DeviceState {
         self.seen.store(false, atomic::Ordering::Relaxed);
     }
 
-    pub async fn check_state_from_device(
-        &self,
-        modbus_context: &mut ModbusContext,
-    ) -> anyhow::Result<()> {
-        modbus_context.set_slave(Slave(self.config.modbus_address));
-        if let Ok(hardware_version_res) = timeout(
-            Duration::from_secs(1),
-            modbus_context.read_hardware_version(),
-        )
-        .await
-        {
-            let hardware_version = hardware_version_res?;
-            *self.version.write().unwrap() = Some(hardware_version);
-            self.seen.store(true, atomic::Ordering::Relaxed);
+    /// Whether both states describe the same device on the same bus
+    pub fn is_same_device(&self, other: &DeviceState) -> bool {
+        self.name == other.name
+            && self.config.bus == other.config.bus
+            && self.config.modbus_address == other.config.modbus_address
+    }
+
+    /// Take over the state of the device from an old state, e.g. after a config reload
+    pub fn keep_state_from(&self, old: &DeviceState) {
+        *self.version.write().unwrap() = *old.version.read().unwrap();
+        *self.last_seen.write().unwrap() = *old.last_seen.read().unwrap();
+        *self.circuit_breaker.write().unwrap() = *old.circuit_breaker.read().unwrap();
+        self.seen.store(
+            old.seen.load(atomic::Ordering::Relaxed),
+            atomic::Ordering::Relaxed,
+        );
+        self.single_writes_only.store(
+            old.single_writes_only.load(atomic::Ordering::Relaxed),
+            atomic::Ordering::Relaxed,
+        );
+        self.retries.store(
+            old.retries.load(atomic::Ordering::Relaxed),
+            atomic::Ordering::Relaxed,
+        );
+        self.consecutive_failures.store(
+            old.consecutive_failures.load(atomic::Ordering::Relaxed),
+            atomic::Ordering::Relaxed,
+        );
+    }
+
+    /// Whether the circuit breaker of the device is open
+    pub fn is_offline(&self) -> bool {
+        *self.circuit_breaker.read().unwrap() == CircuitBreakerState::Open
+    }
+
+    /// Fail fast if the device is offline
+    pub fn ensure_online(&self) -> StateResult<()> {
+        if self.is_offline() {
+            Err(StateError::DeviceOffline(self.name.clone()))
         } else {
+            Ok(())
+        }
+    }
+
+    /// Record a successful Modbus operation and close the circuit breaker
+    pub fn record_success(&self) {
+        self.consecutive_failures
+            .store(0, atomic::Ordering::Relaxed);
+        let mut circuit_breaker = self.circuit_breaker.write().unwrap();
+        if *circuit_breaker == CircuitBreakerState::Open {
+            info!(event = "device-online", name = %self.name, "device is online again");
+            *circuit_breaker = CircuitBreakerState::Closed;
+        }
+    }
+
+    /// Record a failed Modbus operation and open the circuit breaker
+    /// after too many consecutive failures
+    pub fn record_failure(&self) {
+        let failures = self
+            .consecutive_failures
+            .fetch_add(1, atomic::Ordering::Relaxed)
+            + 1;
+        if self.failure_threshold == 0 || failures < self.failure_threshold {
+            return;
+        }
+        let mut circuit_breaker = self.circuit_breaker.write().unwrap();
+        if *circuit_breaker == CircuitBreakerState::Closed {
             warn!(
-                self.config.modbus_address,
-                "Could not read hardware version of device"
+                event = "device-offline",
+                name = %self.name,
+                failures,
+                "device does not answer, marking it offline"
             );
+            *circuit_breaker = CircuitBreakerState::Open;
         }
+    }
+
+    /// Read the hardware version of the device
+    ///
+    /// Marks the device as seen on success and as not seen otherwise.
+    /// This is also sent to offline devices to probe them.
+    pub async fn check_state_from_device(&self, modbus_context: &mut Bus) -> StateResult<()> {
+        let mut retry = Retry::new(self, "read hardware version");
+        let res = loop {
+            modbus_context.select_slave(Slave(self.config.modbus_address));
+            let res =
+                modbus_result(timeout(self.timeout, modbus_context.read_hardware_version()).await);
+            match res {
+                Err(err) if retry.should_retry(&err) => retry.backoff(&err).await,
+                res => break retry.finish(res),
+            }
+        };
 
-        Ok(())
+        match res {
+            Ok(hardware_version) => {
+                *self.version.write().unwrap() = Some(hardware_version);
+                *self.last_seen.write().unwrap() = Some(unix_timestamp());
+                self.seen.store(true, atomic::Ordering::Relaxed);
+                Ok(())
+            }
+            Err(err) => {
+                self.seen.store(false, atomic::Ordering::Relaxed);
+                Err(err)
+            }
+        }
+    }
+
+    /// Write the states of several coils of this device
+    ///
+    /// Coils with contiguous addresses are written with a single request.
+    /// Gaps between the coils are filled with the known states of the other `device_coils`.
+    /// Falls back to single writes if the device does not support writing multiple coils.
+    /// Returns the result of each write in the given order, with the number of retries.
+    pub async fn write_coils(
+        &self,
+        modbus_context: &mut Bus,
+        writes: &[(&CoilState, bool)],
+        device_coils: &[&CoilState],
+    ) -> Vec<StateResult<u32>> {
+        if let Err(err) = self.ensure_online() {
+            return vec![Err(err); writes.len()];
+        }
+
+        let known_values: BTreeMap<u16, bool> = device_coils
+            .iter()
+            .filter_map(|coil| match coil.raw_status() {
+                CoilValue::On => Some((coil.config.address, true)),
+                CoilValue::Off => Some((coil.config.address, false)),
+                CoilValue::Unknown => None,
+            })
+            .collect();
+
+        let mut order: Vec<usize> = (0..writes.len()).collect();
+        order.sort_by_key(|&idx| writes[idx].0.config.address);
+
+        // group the writes into ranges which can be written with a single request
+        let mut ranges: Vec<Vec<usize>> = Vec::new();
+        for idx in order {
+            let address = writes[idx].0.config.address;
+            match ranges.last_mut() {
+                Some(range)
+                    if (writes[*range.last().unwrap()].0.config.address + 1..address)
+                        .all(|gap| known_values.contains_key(&gap)) =>
+                {
+                    range.push(idx)
+                }
+                _ => ranges.push(vec![idx]),
+            }
+        }
+
+        let mut results: Vec<StateResult<u32>> = vec![Ok(0); writes.len()];
+        for range in ranges {
+            if range.len() > 1 && !self.single_writes_only.load(atomic::Ordering::Relaxed) {
+                let range_writes: Vec<_> = range.iter().map(|&idx| writes[idx]).collect();
+                match self
+                    .write_coil_range(modbus_context, &range_writes, &known_values)
+                    .await
+                {
+                    Ok(retries) => {
+                        for &idx in &range {
+                            results[idx] = Ok(retries);
+                        }
+                        continue;
+                    }
+                    Err(StateError::Dorfbus(err)) if err.is_illegal_function() => {
+                        warn!(
+                            name = %self.name,
+                            "device does not support writing multiple coils, using single writes"
+                        );
+                        self.single_writes_only
+                            .store(true, atomic::Ordering::Relaxed);
+                    }
+                    Err(err) => {
+                        for &idx in &range {
+                            results[idx] = Err(err.clone());
+                        }
+                        continue;
+                    }
+                }
+            }
+
+            for idx in range {
+                let (coil, value) = writes[idx];
+                results[idx] = coil.write_coil(modbus_context, value).await;
+            }
+        }
+
+        results
+    }
+
+    /// Write a range of coils with a single request
+    ///
+    /// Returns the number of retries.
+    async fn write_coil_range(
+        &self,
+        modbus_context: &mut Bus,
+        writes: &[(&CoilState, bool)],
+        known_values: &BTreeMap<u16, bool>,
+    ) -> StateResult<u32> {
+        let first = writes[0].0.config.address;
+        let last = writes[writes.len() - 1].0.config.address;
+        let values: Vec<bool> = (first..=last)
+            .map(|address| {
+                writes
+                    .iter()
+                    .rev()
+                    .find(|(coil, _)| coil.config.address == address)
+                    .map(|(coil, value)| coil.config.relais_value(*value))
+                    .or_else(|| known_values.get(&address).copied())
+                    .unwrap_or_default()
+            })
+            .collect();
+
+        let mut retry = Retry::new(self, "write coils");
+        let res = loop {
+            modbus_context.select_slave(Slave(self.config.modbus_address));
+            let res = modbus_result(
+                timeout(self.timeout, modbus_context.write_coils(first, &values)).await,
+            );
+            match res {
+                Err(err) if retry.should_retry(&err) => retry.backoff(&err).await,
+                res => break retry.finish(res),
+            }
+        };
+
+        match res {
+            Ok(()) => {
+                for (coil, value) in writes {
+                    coil.set_status(*value);
+                }
+                Ok(retry.retries())
+            }
+            Err(err) => {
+                for (coil, _) in writes {
+                    coil.reset();
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Read the states of the given coils from the device
+    ///
+    /// All coils must belong to this device.
+    /// A single request covering the address range of all coils is sent.
+    /// Returns the number of retries.
+    pub async fn read_coils_from_device(
+        &self,
+        modbus_context: &mut Bus,
+        coils: &[&CoilState],
+    ) -> StateResult<u32> {
+        self.ensure_online()?;
+        let first = match coils.iter().map(|coil| coil.config.address).min() {
+            Some(first) => first,
+            None => return Ok(0),
+        };
+        let last = coils
+            .iter()
+            .map(|coil| coil.config.address)
+            .max()
+            .unwrap_or(first);
+
+        let mut retry = Retry::new(self, "read coils");
+        let res = loop {
+            modbus_context.select_slave(Slave(self.config.modbus_address));
+            let res = modbus_result(
+                timeout(
+                    self.timeout,
+                    modbus_context.read_coil_states(first, last - first + 1),
+                )
+                .await,
+            );
+            match res {
+                Err(err) if retry.should_retry(&err) => retry.backoff(&err).await,
+                res => break retry.finish(res),
+            }
+        };
+
+        match res {
+            Ok(values) => {
+                for coil in coils {
+                    let value = values[usize::from(coil.config.address - first)];
+                    coil.set_status(coil.config.relais_value(value));
+                }
+                Ok(retry.retries())
+            }
+            Err(err) => {
+                coils.iter().for_each(|coil| coil.reset());
+                Err(err)
+            }
+        }
     }
 }
 
@@ -89,6 +403,24 @@ pub struct CoilState {
     #[serde(skip)]
     pub device: Arc<DeviceState>,
     pub status: Arc<RwLock<CoilValue>>,
+    /// Remaining time in milliseconds until the coil is switched off automatically
+    #[serde(rename = "auto-off-ms", serialize_with = "serialize_remaining_ms")]
+    #[schemars(with = "Option<u64>")]
+    pub auto_off: Arc<RwLock<Option<SystemTime>>>,
+    /// Last status which was read from or written to the device, kept while the device is lost
+    #[serde(skip)]
+    pub last_known: Arc<RwLock<Option<bool>>>,
+}
+
+fn serialize_remaining_ms<S: Serializer>(
+    auto_off: &Arc<RwLock<Option<SystemTime>>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let remaining = auto_off.read().unwrap().map(|off_at| {
+        let remaining = off_at.duration_since(SystemTime::now()).unwrap_or_default();
+        u64::try_from(remaining.as_millis()).unwrap_or(u64::MAX)
+    });
+    remaining.serialize(serializer)
 }
 
 impl CoilState {
@@ -97,6 +429,34 @@ impl CoilState {
         *self.status.write().unwrap() = CoilValue::Unknown;
     }
 
+    /// Record a status which was read from or written to the device
+    ///
+    /// A coil with a maximum on time which is on starts its auto-off timer,
+    /// no matter whether it was switched on by dorfbusd, its default status or by hand.
+    /// A coil which is off cancels its timer.
+    pub fn set_status(&self, value: bool) {
+        *self.status.write().unwrap() = CoilValue::from(value);
+        *self.last_known.write().unwrap() = Some(value);
+
+        let mut auto_off = self.auto_off.write().unwrap();
+        match self.config.max_on_time() {
+            Some(max_on_time) if value => {
+                auto_off.get_or_insert_with(|| SystemTime::now() + max_on_time);
+            }
+            _ => *auto_off = None,
+        }
+    }
+
+    /// State of the relais, which differs from the state of the load if the coil is inverted
+    pub fn raw_status(&self) -> CoilValue {
+        let status = *self.status.read().unwrap();
+        if self.config.inverted {
+            status.inverted()
+        } else {
+            status
+        }
+    }
+
     pub fn as_update(&self) -> CoilUpdate {
         CoilUpdate {
             name: self.name.clone(),
@@ -104,48 +464,93 @@ impl CoilState {
             device_id: self.device.config.modbus_address,
             coil_id: self.config.address,
             status: *self.status.read().unwrap(),
+            raw_status: self.raw_status(),
+            retries: 0,
         }
     }
 
-    /// Set and write the state of a coil
-    pub async fn set_coil(
-        &self,
-        modbus_context: Arc<tokio::sync::Mutex<ModbusContext>>,
-        value: bool,
-    ) -> StateResult<CoilUpdate> {
-        let (tx, rx) = oneshot::channel();
-        let cloned = self.clone();
+    /// Write the state of the coil to the device
+    ///
+    /// The caller has to hold the lock of the modbus context.
+    /// Returns the number of retries.
+    pub async fn write_coil(&self, modbus_context: &mut Bus, value: bool) -> StateResult<u32> {
+        self.device.ensure_online()?;
+        let mut retry = Retry::new(&self.device, "write coil");
+        let res = loop {
+            modbus_context.select_slave(Slave(self.device.config.modbus_address));
+            let res = modbus_result(
+                timeout(
+                    self.device.timeout,
+                    modbus_context.write_coil(self.config.address, self.config.relais_value(value)),
+                )
+                .await,
+            );
+            match res {
+                Err(err) if retry.should_retry(&err) => retry.backoff(&err).await,
+                res => break retry.finish(res),
+            }
+        };
 
-        tokio::spawn(async move {
-            let mut modbus_context = modbus_context.lock().await;
-            modbus_context.set_slave(Slave(cloned.device.config.modbus_address));
+        match res {
+            Ok(()) => {
+                self.set_status(value);
+                Ok(retry.retries())
+            }
+            Err(err) => {
+                *self.status.write().unwrap() = CoilValue::Unknown;
+                Err(err)
+            }
+        }
+    }
 
-            info!(value, name = ?cloned.name, "set coil");
+    /// Switch the coil to the opposite of its current state
+    ///
+    /// If the state of the coil is unknown, it is read from the device first.
+    /// The caller has to hold the lock of the modbus context,
+    /// so no other command is sent between reading and writing the coil.
+    /// Returns the written value and the number of retries.
+    pub async fn toggle_coil(&self, modbus_context: &mut Bus) -> StateResult<(bool, u32)> {
+        let mut retries = 0;
+        let status = *self.status.read().unwrap();
+        if let CoilValue::Unknown = status {
+            retries += self
+                .device
+                .read_coils_from_device(modbus_context, &[self])
+                .await?;
+        }
 
-            let _tx_result = tx.send(
-                match timeout(
-                    Duration::from_secs(1),
-                    modbus_context.write_single_coil(cloned.config.address, value),
-                )
-                .await
-                {
-                    Ok(Ok(())) => {
-                        *cloned.status.write().unwrap() = CoilValue::from(value);
-                        Ok(cloned.as_update())
-                    }
-                    Ok(Err(err)) => {
-                        *cloned.status.write().unwrap() = CoilValue::Unknown;
-                        Err(err.into())
-                    }
-                    Err(_) => {
-                        *cloned.status.write().unwrap() = CoilValue::Unknown;
-                        Err(StateError::Timeout)
-                    }
-                },
-            );
-        });
+        let value = match *self.status.read().unwrap() {
+            CoilValue::On => false,
+            CoilValue::Off | CoilValue::Unknown => true,
+        };
+        retries += self.write_coil(modbus_context, value).await?;
+        Ok((value, retries))
+    }
 
-        rx.await?
+    /// Value which should be written to the coil if its device is reset
+    ///
+    /// A `restore_value` takes precedence over the configured default status.
+    /// Returns `None` if the coil is configured as `do-not-set`.
+    pub fn reset_value(&self, restore_value: Option<bool>) -> Option<bool> {
+        match (restore_value, &self.config.default_status) {
+            (Some(value), _) => Some(value),
+            (None, ResetCoilStatus::On) => Some(true),
+            (None, ResetCoilStatus::Off) => Some(false),
+            (None, ResetCoilStatus::DoNotSet) => None,
+        }
+    }
+
+    /// Read the state of the coil from the device
+    ///
+    /// The read is queued on the bus of the coil with the given priority.
+    pub async fn refresh_coil(
+        &self,
+        queue: &BusQueue,
+        priority: Priority,
+    ) -> StateResult<CoilUpdate> {
+        info!(name = ?self.name, "read coil");
+        let retries = queue.read_coils(&self.device, &[self], priority).await??;
+        Ok(self.as_update().with_retries(retries))
     }
 
     /// Get the state of a coil
@@ -166,7 +571,17 @@ pub struct CoilUpdate {
     pub device_id: u8,
     /// Id of the coil on the relais card
     pub coil_id: u16,
-    status: CoilValue,
+    pub status: CoilValue,
+    /// Status of the relais, the opposite of the status if the coil is inverted
+    pub raw_status: CoilValue,
+    /// Number of retries which were needed to read or write the coil
+    pub retries: u32,
+}
+
+impl CoilUpdate {
+    pub fn with_retries(self, retries: u32) -> CoilUpdate {
+        CoilUpdate { retries, ..self }
+    }
 }
 
 #[derive(Serialize, Debug, Copy, Clone, JsonSchema)]
@@ -177,6 +592,17 @@ pub enum CoilValue {
     Unknown,
 }
 
+impl CoilValue {
+    /// The opposite value, an unknown value stays unknown
+    pub fn inverted(self) -> CoilValue {
+        match self {
+            CoilValue::On => CoilValue::Off,
+            CoilValue::Off => CoilValue::On,
+            CoilValue::Unknown => CoilValue::Unknown,
+        }
+    }
+}
+
 impl From<bool> for CoilValue {
     fn from(v: bool) -> Self {
         if v {
@@ -197,22 +623,43 @@ impl TryFrom<&Config> for BusState {
     type Error = anyhow::Error;
 
     fn try_from(config: &Config) -> Result<Self, Self::Error> {
-        let devices: BTreeMap<_, _> = config
+        let devices_res: anyhow::Result<BTreeMap<_, _>> = config
             .devices
             .iter()
             .map(|(name, device)| {
-                (
+                let bus_exists = if config.buses.is_empty() {
+                    device.bus == DEFAULT_BUS
+                } else {
+                    config.buses.contains_key(&device.bus)
+                };
+                if !bus_exists {
+                    return Err(anyhow::Error::msg(format!(
+                        "device {} is connected to bus {} which does not exist",
+                        name, device.bus,
+                    )));
+                }
+                Ok((
                     name.to_owned(),
                     Arc::new(DeviceState {
                         name: name.clone(),
                         config: device.clone(),
                         version: RwLock::new(None),
                         seen: AtomicBool::from(false),
+                        last_seen: RwLock::new(None),
+                        retries: AtomicU64::from(0),
+                        single_writes_only: AtomicBool::from(false),
+                        retry_policy: device.retry.clone().unwrap_or_else(|| config.retry.clone()),
+                        timeout: config.device_timeout(device),
+                        circuit_breaker: RwLock::new(CircuitBreakerState::Closed),
+                        consecutive_failures: AtomicU32::from(0),
+                        failure_threshold: config.circuit_breaker.failure_threshold,
                     }),
-                )
+                ))
             })
             .collect();
 
+        let devices = devices_res?;
+
         let coils_res: anyhow::Result<BTreeMap<_, _>> = config
             .coils
             .iter()
@@ -233,6 +680,8 @@ impl TryFrom<&Config> for BusState {
                         config: coil.to_owned(),
                         device,
                         status: Default::default(),
+                        auto_off: Default::default(),
+                        last_known: Default::default(),
                     }),
                 ))
             })
@@ -265,14 +714,466 @@ impl BusState {
         self.coils.values().for_each(|state| state.reset());
     }
 
-    pub async fn check_state_from_device(&self, state: &State) -> anyhow::Result<()> {
-        let mut modbus_context = state.modbus().lock().await;
+    /// Take over the state of all devices and coils which did not change from an old bus state
+    ///
+    /// The auto-off timers of all coils which still exist are kept.
+    /// A coil which was inverted or is no longer inverted keeps the state of its relais.
+    /// The other devices and coils are unknown and are read from the bus with the next poll.
+    pub fn keep_state_from(&self, old: &BusState) {
+        for (name, device) in self.devices.iter() {
+            match old.devices.get(name) {
+                Some(old_device) if device.is_same_device(old_device) => {
+                    device.keep_state_from(old_device)
+                }
+                _ => {}
+            }
+        }
+
+        for (name, coil) in self.coils.iter() {
+            // the auto-off timer belongs to the coil name, like its persisted value
+            if let Some(old_coil) = old.coils.get(name) {
+                *coil.auto_off.write().unwrap() = *old_coil.auto_off.read().unwrap();
+            }
+            match old.coils.get(name) {
+                Some(old_coil)
+                    if coil.config.address == old_coil.config.address
+                        && coil.device.is_same_device(&old_coil.device) =>
+                {
+                    // the relais keeps its state, so the load flips if the coil became inverted
+                    let flipped = coil.config.inverted != old_coil.config.inverted;
+                    let status = *old_coil.status.read().unwrap();
+                    let last_known = *old_coil.last_known.read().unwrap();
+                    *coil.status.write().unwrap() =
+                        if flipped { status.inverted() } else { status };
+                    *coil.last_known.write().unwrap() = last_known.map(|value| value != flipped);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// All coils which belong to the device with the given name
+    pub fn device_coils(&self, device: &str) -> Vec<&CoilState> {
+        self.coils
+            .values()
+            .filter(|coil| coil.config.device == device)
+            .map(|coil| coil.as_ref())
+            .collect()
+    }
+
+    /// Write the configured default status to the given coils
+    ///
+    /// Call this after a device (re)appeared on the bus, e.g. on startup or after a powerloss.
+    /// The persisted values are restored instead if the restore policy is `restore`.
+    /// The interlock groups of the coils are checked, see [`State::restore_coils`].
+    async fn apply_default_status(state: &State, coils: &[&CoilState]) {
+        let writes: Vec<_> = coils
+            .iter()
+            .filter_map(|coil| {
+                let restore_value = state.restore_value(&coil.name);
+                coil.reset_value(restore_value)
+                    .map(|value| (*coil, value, restore_value.is_some()))
+            })
+            .collect();
+        let coil_values: Vec<_> = writes
+            .iter()
+            .map(|(coil, value, _)| (*coil, *value))
+            .collect();
+        let results = state.restore_coils(&coil_values, Priority::Low).await;
+
+        for ((coil, value, restored), result) in writes.into_iter().zip(results) {
+            match result {
+                Ok(_) if restored => info!(
+                    event = "coil-restore",
+                    name = %coil.name,
+                    device = %coil.device.name,
+                    value,
+                    "restored persisted coil status"
+                ),
+                Ok(_) => info!(
+                    event = "coil-reset",
+                    name = %coil.name,
+                    device = %coil.device.name,
+                    value,
+                    "reset coil to its default status"
+                ),
+                Err(err) => warn!(
+                    event = "coil-reset-failed",
+                    name = %coil.name,
+                    device = %coil.device.name,
+                    %err,
+                    "could not reset coil to its default status"
+                ),
+            }
+        }
+    }
+
+    /// Write the last desired status to the given coils
+    ///
+    /// Call this after a device reappeared which kept its coil states,
+    /// so a write which got lost while the device did not answer is repeated.
+    /// The desired status is the persisted value, otherwise the status before the device was lost.
+    /// Momentary coils are switched off.
+    /// The interlock groups of the coils are checked, like for the default status.
+    async fn rewrite_desired_status(
+        state: &State,
+        coils: &[&CoilState],
+        last_known: &[Option<bool>],
+    ) {
+        let writes: Vec<_> = coils
+            .iter()
+            .zip(last_known)
+            .filter_map(|(coil, known)| {
+                let desired = state
+                    .state_store()
+                    .and_then(|store| store.coil_value(&coil.name))
+                    .or(*known)?;
+                Some((*coil, desired && coil.config.mode != CoilMode::Momentary))
+            })
+            .collect();
+
+        let results = state.restore_coils(&writes, Priority::Low).await;
+        for ((coil, _), result) in writes.into_iter().zip(results) {
+            if let Err(err) = result {
+                warn!(
+                    name = %coil.name,
+                    device = %coil.device.name,
+                    %err,
+                    "could not rewrite coil status"
+                );
+            }
+        }
+    }
+
+    /// Whether a device which reappeared probably lost its power, so its coils are reset
+    ///
+    /// This is assumed on the first contact with the device, after the circuit breaker opened
+    /// and if a coil state which was read back differs from the state before the device was lost.
+    fn power_loss_likely(
+        was_offline: bool,
+        coils: &[&CoilState],
+        last_known: &[Option<bool>],
+    ) -> bool {
+        if was_offline || last_known.iter().all(Option::is_none) {
+            return true;
+        }
+        coils.iter().zip(last_known).any(|(coil, known)| {
+            matches!(
+                (*coil.status.read().unwrap(), known),
+                (CoilValue::On, Some(false)) | (CoilValue::Off, Some(true))
+            )
+        })
+    }
+
+    /// Check all devices and read the coil states of the devices which answered
+    ///
+    /// Offline devices are skipped, they are checked by [`BusState::probe_offline_devices`].
+    /// Errors are logged per device and do not abort the check of the other devices.
+    pub async fn check_state_from_device(&self, state: &State) {
+        for (name, device) in self.devices.iter() {
+            if device.is_offline() {
+                debug!(%name, "skip offline device");
+                continue;
+            }
+            self.check_device(state, name, device).await;
+        }
+    }
 
+    /// Check all offline devices
+    ///
+    /// A device which answers is online again and is handled like a device which reappeared.
+    pub async fn probe_offline_devices(&self, state: &State) {
         for (name, device) in self.devices.iter() {
-            info!(%name, device.config.modbus_address, "read hardware version of device");
-            device.check_state_from_device(&mut modbus_context).await?;
+            if device.is_offline() {
+                debug!(%name, "probe offline device");
+                self.check_device(state, name, device).await;
+            }
+        }
+    }
+
+    /// Check a device and read its coil states if it answered
+    ///
+    /// Every step is queued on the bus of the device with a low priority,
+    /// so requests of clients are sent in between.
+    async fn check_device(&self, state: &State, name: &str, device: &Arc<DeviceState>) {
+        let was_seen = device.seen.load(atomic::Ordering::Relaxed);
+        let was_offline = device.is_offline();
+        let coils = self.device_coils(name);
+
+        let queue = match state.device_queue(device) {
+            Ok(queue) => queue,
+            Err(err) => {
+                warn!(%name, %err, "could not check device");
+                return;
+            }
+        };
+
+        debug!(%name, device.config.modbus_address, "read hardware version of device");
+        let checked = queue.check_device(device, Priority::Low).await;
+        if let Err(err) = checked.map_err(StateError::from).and_then(|res| res) {
+            if was_seen {
+                warn!(%name, %err, "lost device");
+            } else {
+                debug!(%name, %err, "could not read hardware version of device");
+            }
+            coils.iter().for_each(|coil| coil.reset());
+            return;
+        }
+        if !was_seen {
+            info!(%name, version = ?*device.version.read().unwrap(), "found device");
+            // a single missed poll must not overwrite the coils with their defaults
+            let last_known: Vec<_> = coils
+                .iter()
+                .map(|coil| *coil.last_known.read().unwrap())
+                .collect();
+            Self::read_coils(queue, name, device, &coils).await;
+            if Self::power_loss_likely(was_offline, &coils, &last_known) {
+                Self::apply_default_status(state, &coils).await;
+            } else {
+                debug!(%name, "device kept its coil states");
+                Self::rewrite_desired_status(state, &coils, &last_known).await;
+            }
         }
 
-        Ok(())
+        debug!(%name, coil_count = coils.len(), "read coil states of device");
+        Self::read_coils(queue, name, device, &coils).await;
+        if !was_seen {
+            Self::switch_off_momentary(state, &coils).await;
+        }
+    }
+
+    /// Read the coil states of a device, errors are logged
+    async fn read_coils(
+        queue: &BusQueue,
+        name: &str,
+        device: &Arc<DeviceState>,
+        coils: &[&CoilState],
+    ) {
+        let read = queue.read_coils(device, coils, Priority::Low).await;
+        if let Err(err) = read.map_err(StateError::from).and_then(|res| res) {
+            warn!(%name, %err, "could not read coil states of device");
+        }
+    }
+
+    /// Switch off the momentary coils which are on
+    ///
+    /// Call this after a device (re)appeared, a pulse may have been cut short
+    /// by a restart of dorfbusd or while the device did not answer.
+    async fn switch_off_momentary(state: &State, coils: &[&CoilState]) {
+        let writes: Vec<_> = coils
+            .iter()
+            .filter(|coil| {
+                coil.config.mode == CoilMode::Momentary
+                    && matches!(*coil.status.read().unwrap(), CoilValue::On)
+            })
+            .map(|coil| (*coil, false))
+            .collect();
+
+        let results = state.restore_coils(&writes, Priority::Low).await;
+        for ((coil, _), result) in writes.into_iter().zip(results) {
+            match result {
+                Ok(_) => info!(
+                    event = "momentary-off",
+                    name = %coil.name,
+                    device = %coil.device.name,
+                    "switched off momentary coil which was left on"
+                ),
+                Err(err) => warn!(
+                    name = %coil.name,
+                    device = %coil.device.name,
+                    %err,
+                    "could not switch off momentary coil"
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio_modbus::{
+        client::Context as ModbusContext,
+        prelude::{Client, Slave},
+    };
+
+    use super::{BusState, CoilState, CoilValue, DeviceState};
+    use crate::{
+        bus_queue::Priority,
+        config::{CoilConfig, Config, DeviceConfig},
+        simulation::{simulated_state, SimulatedBus, SimulatedCard, SimulatedCards},
+        state::Bus,
+    };
+
+    /// A coil of a simulated card with 8 coils at modbus address 1
+    fn simulated_coil(name: &str, config: CoilConfig) -> (SimulatedCards, Bus, CoilState) {
+        let cards = SimulatedCards::default();
+        cards.lock().unwrap().insert(1, SimulatedCard::new(8));
+        let client: Box<dyn Client> = Box::new(SimulatedBus::new(cards.clone()));
+        let modbus: Bus = Box::new(ModbusContext::from(client));
+
+        let coil = CoilState {
+            name: name.to_owned(),
+            config,
+            device: Arc::new(DeviceState {
+                name: "card".to_owned(),
+                config: DeviceConfig {
+                    modbus_address: 1,
+                    ..Default::default()
+                },
+                timeout: Duration::from_millis(100),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        (cards, modbus, coil)
+    }
+
+    #[tokio::test]
+    async fn toggle_reads_unknown_state_first() {
+        let (cards, mut modbus, coil) = simulated_coil(
+            "relay",
+            CoilConfig {
+                address: 3,
+                ..Default::default()
+            },
+        );
+        cards.lock().unwrap().get_mut(&1).unwrap().coils[3] = true;
+
+        assert_eq!(coil.toggle_coil(&mut modbus).await.unwrap(), (false, 0));
+        assert!(!cards.lock().unwrap()[&1].coils[3]);
+        assert!(matches!(*coil.status.read().unwrap(), CoilValue::Off));
+
+        assert_eq!(coil.toggle_coil(&mut modbus).await.unwrap(), (true, 0));
+        assert!(cards.lock().unwrap()[&1].coils[3]);
+    }
+
+    #[tokio::test]
+    async fn inverted_coil_writes_opposite_relais_value() {
+        let (cards, mut modbus, coil) = simulated_coil(
+            "heater",
+            CoilConfig {
+                address: 2,
+                inverted: true,
+                ..Default::default()
+            },
+        );
+
+        coil.write_coil(&mut modbus, true).await.unwrap();
+        assert!(!cards.lock().unwrap()[&1].coils[2]);
+        assert!(matches!(coil.as_update().raw_status, CoilValue::Off));
+
+        cards.lock().unwrap().get_mut(&1).unwrap().coils[2] = true;
+        coil.device
+            .read_coils_from_device(&mut modbus, &[&coil])
+            .await
+            .unwrap();
+        assert!(matches!(*coil.status.read().unwrap(), CoilValue::Off));
+    }
+
+    #[test]
+    fn power_loss_only_after_outage_or_changed_coils() {
+        let coil = CoilState::default();
+        coil.set_status(true);
+
+        assert!(!BusState::power_loss_likely(false, &[&coil], &[Some(true)]));
+        assert!(BusState::power_loss_likely(false, &[&coil], &[None]));
+        assert!(BusState::power_loss_likely(true, &[&coil], &[Some(true)]));
+        assert!(BusState::power_loss_likely(false, &[&coil], &[Some(false)]));
+        assert!(BusState::power_loss_likely(false, &[], &[]));
+
+        coil.reset();
+        assert!(!BusState::power_loss_likely(
+            false,
+            &[&coil],
+            &[Some(false)]
+        ));
+    }
+
+    #[test]
+    fn coil_seen_on_starts_auto_off_timer() {
+        let coil = CoilState {
+            config: CoilConfig {
+                max_on_time_ms: Some(60_000),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        coil.set_status(true);
+        let off_at = coil.auto_off.read().unwrap().unwrap();
+        coil.set_status(true);
+        assert_eq!(*coil.auto_off.read().unwrap(), Some(off_at));
+
+        coil.set_status(false);
+        assert_eq!(*coil.auto_off.read().unwrap(), None);
+    }
+
+    #[test]
+    fn keep_raw_status_if_coil_became_inverted() {
+        let mut old = BusState::default();
+        let old_coil = CoilState::default();
+        old_coil.set_status(true);
+        old.coils.insert("fan".to_owned(), Arc::new(old_coil));
+
+        let mut new = BusState::default();
+        let coil = CoilState {
+            config: CoilConfig {
+                inverted: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        new.coils.insert("fan".to_owned(), Arc::new(coil));
+
+        new.keep_state_from(&old);
+        let coil = &new.coils["fan"];
+        assert!(matches!(*coil.status.read().unwrap(), CoilValue::Off));
+        assert!(matches!(coil.raw_status(), CoilValue::On));
+        assert_eq!(*coil.last_known.read().unwrap(), Some(false));
+    }
+
+    #[tokio::test]
+    async fn momentary_coil_left_on_is_switched_off() {
+        let config: Config = toml::from_str(
+            r#"
+            [devices.card]
+            modbus-address = 1
+
+            [coils.door-buzzer]
+            device = "card"
+            address = 4
+            default-status = "off"
+            mode = "momentary"
+            "#,
+        )
+        .unwrap();
+        let state = simulated_state(config);
+        let bus_state = state.bus_state();
+        let coil = bus_state.coils["door-buzzer"].as_ref();
+        let queue = state.device_queue(&coil.device).unwrap();
+
+        let raw_coil = |value: Option<bool>| {
+            queue.exclusive(Priority::Normal, move |modbus| {
+                Box::pin(async move {
+                    modbus.select_slave(Slave(1));
+                    if let Some(value) = value {
+                        modbus.write_coil(4, value).await.unwrap();
+                    }
+                    modbus.read_coils(4, 1).await.unwrap()[0]
+                })
+            })
+        };
+        assert!(raw_coil(Some(true)).await.unwrap());
+        queue
+            .read_coils(&coil.device, &[coil], Priority::Normal)
+            .await
+            .unwrap()
+            .unwrap();
+
+        BusState::switch_off_momentary(&state, &[coil]).await;
+        assert!(!raw_coil(None).await.unwrap());
+        assert!(matches!(*coil.status.read().unwrap(), CoilValue::Off));
     }
 }
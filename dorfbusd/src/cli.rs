@@ -1,14 +1,28 @@
-use std::fs::read_dir;
+use std::{fs::read_dir, time::Duration};
 
-use anyhow::Context;
+use anyhow::{ensure, Context};
 use clap::{crate_authors, crate_description, crate_name, crate_version, App, Arg};
 use tracing::warn;
+
+use crate::{config_loader::ConfigFormat, persistence::RestorePolicy, transport::Transport};
+
 #[derive(Clone)]
 pub struct Params {
     pub port: u16,
-    pub serial_path: String,
-    pub serial_boud: u32,
+    /// Transport of the `default` bus, if one was given or a serial device was found
+    pub transport: Option<Transport>,
     pub config_path: String,
+    /// Format of the config file, picked by its extension if not given
+    pub config_format: Option<ConfigFormat>,
+    pub poll_interval: Duration,
+    /// Deadline of HTTP requests which access the bus
+    pub request_timeout: Duration,
+    pub state_path: Option<String>,
+    pub restore_policy: RestorePolicy,
+    /// Simulate the configured devices instead of connecting to a bus
+    pub simulate: bool,
+    /// Only check the config file and exit
+    pub check_config: bool,
 }
 
 pub fn app() -> anyhow::Result<Params> {
@@ -23,6 +37,13 @@ pub fn app() -> anyhow::Result<Params> {
             .default_value("8080")
             .env("HTTP_PORT"),
         )
+        .arg(
+            Arg::from_usage(
+                "--bus=[BUS_URL] 'URL of the bus, rtu://<serial path> or tcp://<host>:<port>'",
+            )
+            .conflicts_with("serial-path")
+            .env("BUS_URL"),
+        )
         .arg(
             Arg::from_usage("-s, --serial-path=[SERIAL_PATH] 'Path to the serial device'")
                 .env("SERIAL_PATH"),
@@ -33,10 +54,51 @@ pub fn app() -> anyhow::Result<Params> {
                 .env("SERIAL_BOUD"),
         )
         .arg(
-            Arg::from_usage("-c, --config=[CONFIG] 'Path to the configuration file'")
+            Arg::from_usage("-c, --config=[CONFIG] 'Path to the configuration file or a directory of configuration files'")
                 .default_value("config.toml")
                 .env("CONFIG"),
         )
+        .arg(
+            Arg::from_usage(
+                "--config-format=[FORMAT] 'Format of the configuration file, by default picked by its extension'",
+            )
+            .possible_values(&["toml", "yaml", "json"])
+            .env("CONFIG_FORMAT"),
+        )
+        .arg(
+            Arg::from_usage(
+                "-i, --poll-interval=[SECONDS] 'Interval in seconds in which all devices are polled'",
+            )
+            .default_value("30")
+            .env("POLL_INTERVAL"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--request-timeout=[SECONDS] 'Deadline in seconds for HTTP requests which access the bus'",
+            )
+            .default_value("10")
+            .env("REQUEST_TIMEOUT"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--state-file=[STATE_FILE] 'Path to the file in which the coil states are persisted'",
+            )
+            .env("STATE_FILE"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--restore-policy=[POLICY] 'Write the persisted coil states to the devices or only use them as initial state'",
+            )
+            .possible_values(&["restore", "seed"])
+            .default_value("seed")
+            .env("RESTORE_POLICY"),
+        )
+        .arg(Arg::from_usage(
+            "--simulate 'Simulate the configured relais cards instead of connecting to a bus'",
+        ))
+        .arg(Arg::from_usage(
+            "--check-config 'Check the configuration file for problems and exit'",
+        ))
         .get_matches();
 
     let port = matches
@@ -44,28 +106,78 @@ pub fn app() -> anyhow::Result<Params> {
         .expect("port number not found")
         .parse()?;
 
-    let serial_path = matches
-        .value_of("serial-path")
-        .map(|s| s.to_owned())
-        .or_else(guess_serial_device)
-        .expect("serial path not found");
-
     let serial_boud = matches
         .value_of("serial-boud")
         .expect("serial boudrate not found")
         .parse()
         .with_context(|| "The specified boud rate is not a valid integer")?;
 
+    let simulate = matches.is_present("simulate");
+    let check_config = matches.is_present("check-config");
+
+    let transport = match matches.value_of("bus") {
+        Some(url) => Some(Transport::parse(url, serial_boud)?),
+        None if simulate || check_config => None,
+        None => matches
+            .value_of("serial-path")
+            .map(|s| s.to_owned())
+            .or_else(guess_serial_device)
+            .map(|path| Transport::Rtu {
+                path,
+                boud: serial_boud,
+            }),
+    };
+
     let config_path = matches
         .value_of("config")
         .expect("config path not found")
         .to_owned();
 
+    let config_format = matches
+        .value_of("config-format")
+        .map(str::parse)
+        .transpose()?;
+
+    let poll_interval_secs: u64 = matches
+        .value_of("poll-interval")
+        .expect("poll interval not found")
+        .parse()
+        .with_context(|| "The specified poll interval is not a valid integer")?;
+    ensure!(
+        poll_interval_secs > 0,
+        "The poll interval must be at least one second"
+    );
+    let poll_interval = Duration::from_secs(poll_interval_secs);
+
+    let request_timeout_secs: u64 = matches
+        .value_of("request-timeout")
+        .expect("request timeout not found")
+        .parse()
+        .with_context(|| "The specified request timeout is not a valid integer")?;
+    ensure!(
+        request_timeout_secs > 0,
+        "The request timeout must be at least one second"
+    );
+    let request_timeout = Duration::from_secs(request_timeout_secs);
+
+    let state_path = matches.value_of("state-file").map(|s| s.to_owned());
+
+    let restore_policy = matches
+        .value_of("restore-policy")
+        .expect("restore policy not found")
+        .parse()?;
+
     Ok(Params {
         port,
-        serial_path,
-        serial_boud,
+        transport,
         config_path,
+        config_format,
+        poll_interval,
+        request_timeout,
+        state_path,
+        restore_policy,
+        simulate,
+        check_config,
     })
 }
 
@@ -73,7 +185,6 @@ fn guess_serial_device() -> Option<String> {
     warn!("Detecting serial device. The serial device should be configured explicitly in a production environment.");
     read_dir("/dev")
         .unwrap()
-        .into_iter()
         .filter_map(|dir_entry| dir_entry.ok())
         .filter(|dir_entry| {
             let name = dir_entry.file_name();
@@ -1,15 +1,440 @@
-use std::collections::{BTreeMap, BTreeSet};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+    time::Duration,
+};
 
+use anyhow::anyhow;
+use chrono::{DateTime, FixedOffset, Local, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
 #[cfg(test)]
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+use crate::bus_queue::Priority;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[serde(rename_all = "kebab-case")]
 #[cfg_attr(test, derive(JsonSchema))]
 pub struct Config {
+    /// Glob patterns of further config files with buses, devices, coils, tags, interlocks, schedules and scenes
+    ///
+    /// The patterns are relative to the directory of the config file.
+    /// Global settings can only be set in the config file itself.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    /// Buses the devices are connected to
+    ///
+    /// If no bus is configured, the bus given on the command line is used as `default` bus.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub buses: BTreeMap<String, BusConfig>,
+    /// Retry policy of the Modbus operations of all devices
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    /// Circuit breaker which marks unresponsive devices offline
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
     pub devices: BTreeMap<String, DeviceConfig>,
+    #[serde(default)]
     pub coils: BTreeMap<String, CoilConfig>,
+    /// Settings of the coils with a tag
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, TagConfig>,
+    /// Delay in milliseconds between switching on two coils of a tag, 0 switches all coils at once
+    #[serde(default)]
+    pub switch_on_delay_ms: u64,
+    /// Groups of coils of which at most one may be on at a time
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub interlocks: BTreeMap<String, InterlockConfig>,
+    /// Actions which run at fixed times
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub schedules: BTreeMap<String, ScheduleConfig>,
+    /// Named presets of the values of several coils
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub scenes: BTreeMap<String, SceneConfig>,
+}
+
+impl Config {
+    /// Timeout of Modbus requests on a bus
+    pub fn bus_timeout(&self, bus: &str) -> Duration {
+        let timeout_ms = self
+            .buses
+            .get(bus)
+            .map(|bus| bus.timeout_ms)
+            .unwrap_or(DEFAULT_TIMEOUT_MS);
+        Duration::from_millis(timeout_ms)
+    }
+
+    /// Check the config for semantic problems
+    ///
+    /// All problems are reported at once, with the TOML keys they were found at.
+    pub fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = Vec::new();
+        let mut error = |key: String, message: String| errors.push(ConfigError { key, message });
+
+        for (name, device) in self.devices.iter() {
+            let bus_exists = if self.buses.is_empty() {
+                device.bus == DEFAULT_BUS
+            } else {
+                self.buses.contains_key(&device.bus)
+            };
+            if !bus_exists {
+                error(
+                    format!("devices.{}.bus", name),
+                    format!("bus {:?} does not exist", device.bus),
+                );
+            }
+            if !(1..=247).contains(&device.modbus_address) {
+                error(
+                    format!("devices.{}.modbus-address", name),
+                    format!(
+                        "address {} is reserved, use an address from 1 to 247",
+                        device.modbus_address
+                    ),
+                );
+            }
+            if let Some(retry) = &device.retry {
+                retry.validate(&format!("devices.{}.retry", name), &mut error);
+            }
+        }
+        self.retry.validate("retry", &mut error);
+
+        let mut device_addresses: BTreeMap<(&str, u8), &str> = BTreeMap::new();
+        for (name, device) in self.devices.iter() {
+            let address = (device.bus.as_str(), device.modbus_address);
+            if let Some(other) = device_addresses.insert(address, name) {
+                error(
+                    format!("devices.{}.modbus-address", name),
+                    format!(
+                        "address {} on bus {:?} is also used by devices.{}",
+                        device.modbus_address, device.bus, other
+                    ),
+                );
+            }
+        }
+
+        let mut coil_addresses: BTreeMap<(&str, u16), &str> = BTreeMap::new();
+        for (name, coil) in self.coils.iter() {
+            if !self.devices.contains_key(&coil.device) {
+                error(
+                    format!("coils.{}.device", name),
+                    format!("device {:?} does not exist", coil.device),
+                );
+            }
+            if let Some(other) = coil_addresses.insert((&coil.device, coil.address), name) {
+                error(
+                    format!("coils.{}.address", name),
+                    format!(
+                        "address {} of device {:?} is also used by coils.{}",
+                        coil.address, coil.device, other
+                    ),
+                );
+            }
+            if coil.mode == CoilMode::Momentary && coil.default_status == ResetCoilStatus::On {
+                error(
+                    format!("coils.{}.default-status", name),
+                    "a momentary coil must not be on by default".to_owned(),
+                );
+            }
+            if coil.max_on_time_ms == Some(0) {
+                error(
+                    format!("coils.{}.max-on-time-ms", name),
+                    "the maximum on time must be greater than 0".to_owned(),
+                );
+            }
+            if coil.max_on_time_ms.is_some() && coil.default_status == ResetCoilStatus::On {
+                error(
+                    format!("coils.{}.default-status", name),
+                    "a coil with a maximum on time must not be on by default".to_owned(),
+                );
+            }
+            for tag in coil.tags.iter().filter(|tag| self.coils.contains_key(*tag)) {
+                error(
+                    format!("coils.{}.tags", name),
+                    format!("tag {:?} has the same name as a coil", tag),
+                );
+            }
+        }
+
+        for (name, tag) in self.tags.iter() {
+            if !self.coils.values().any(|coil| coil.tags.contains(name)) {
+                error(
+                    format!("tags.{}", name),
+                    format!("no coil has the tag {:?}", name),
+                );
+            }
+            for coil in tag.switch_on_order.iter() {
+                if !self
+                    .coils
+                    .get(coil)
+                    .map_or(false, |coil| coil.tags.contains(name))
+                {
+                    error(
+                        format!("tags.{}.switch-on-order", name),
+                        format!("coil {:?} does not have the tag", coil),
+                    );
+                }
+            }
+        }
+
+        let mut interlocked_coils: BTreeMap<&str, &str> = BTreeMap::new();
+        for (name, interlock) in self.interlocks.iter() {
+            if interlock.coils.len() < 2 {
+                error(
+                    format!("interlocks.{}.coils", name),
+                    "an interlock group needs at least two coils".to_owned(),
+                );
+            }
+            for coil in interlock.coils.iter() {
+                if !self.coils.contains_key(coil) {
+                    error(
+                        format!("interlocks.{}.coils", name),
+                        format!("coil {:?} does not exist", coil),
+                    );
+                }
+                if let Some(other) = interlocked_coils.insert(coil, name) {
+                    error(
+                        format!("interlocks.{}.coils", name),
+                        format!("coil {:?} is also in interlocks.{}", coil, other),
+                    );
+                }
+            }
+
+            let default_on = interlock
+                .coils
+                .iter()
+                .filter_map(|coil| self.coils.get(coil))
+                .filter(|coil| coil.default_status == ResetCoilStatus::On)
+                .count();
+            if default_on > 1 {
+                error(
+                    format!("interlocks.{}.coils", name),
+                    "at most one coil of an interlock group may be on by default".to_owned(),
+                );
+            }
+        }
+
+        for (name, schedule) in self.schedules.iter() {
+            if let Err(err) = schedule.cron_schedule() {
+                error(format!("schedules.{}.cron", name), err.to_string());
+            }
+            if let Err(err) = schedule.timezone() {
+                error(format!("schedules.{}.timezone", name), err.to_string());
+            }
+            match (&schedule.coil, &schedule.tag, &schedule.scene) {
+                (Some(coil), None, None) => {
+                    if !self.coils.contains_key(coil) {
+                        error(
+                            format!("schedules.{}.coil", name),
+                            format!("coil {:?} does not exist", coil),
+                        );
+                    }
+                }
+                (None, Some(tag), None) => {
+                    if !self.coils.values().any(|coil| coil.tags.contains(tag)) {
+                        error(
+                            format!("schedules.{}.tag", name),
+                            format!("no coil has the tag {:?}", tag),
+                        );
+                    }
+                }
+                (None, None, Some(scene)) => {
+                    if !self.scenes.contains_key(scene) {
+                        error(
+                            format!("schedules.{}.scene", name),
+                            format!("scene {:?} does not exist", scene),
+                        );
+                    }
+                }
+                _ => error(
+                    format!("schedules.{}", name),
+                    "exactly one of a coil, a tag or a scene has to be switched".to_owned(),
+                ),
+            }
+            match (&schedule.scene, schedule.value) {
+                (None, None) => error(
+                    format!("schedules.{}.value", name),
+                    "the value of the coils is missing".to_owned(),
+                ),
+                (Some(_), Some(_)) => error(
+                    format!("schedules.{}.value", name),
+                    "a scene switches the coils to its own values".to_owned(),
+                ),
+                _ => {}
+            }
+        }
+
+        for (name, scene) in self.scenes.iter() {
+            if scene.coils.is_empty() && scene.tags.is_empty() {
+                error(
+                    format!("scenes.{}", name),
+                    "a scene has to switch at least one coil or tag".to_owned(),
+                );
+            }
+            for coil in scene.coils.keys() {
+                if !self.coils.contains_key(coil) {
+                    error(
+                        format!("scenes.{}.coils", name),
+                        format!("coil {:?} does not exist", coil),
+                    );
+                }
+            }
+            for tag in scene.tags.keys() {
+                if !self.coils.values().any(|coil| coil.tags.contains(tag)) {
+                    error(
+                        format!("scenes.{}.tags", name),
+                        format!("no coil has the tag {:?}", tag),
+                    );
+                }
+            }
+            for (coil_name, coil) in self.coils.iter() {
+                let values: BTreeSet<bool> = coil
+                    .tags
+                    .iter()
+                    .filter_map(|tag| scene.tags.get(tag).copied())
+                    .collect();
+                if values.len() > 1 && !scene.coils.contains_key(coil_name) {
+                    error(
+                        format!("scenes.{}.tags", name),
+                        format!(
+                            "coil {:?} is switched on by one tag and off by another",
+                            coil_name
+                        ),
+                    );
+                }
+            }
+
+            let values = self.scene_values(scene);
+            for (group, interlock) in self.interlocks.iter() {
+                let on = interlock
+                    .coils
+                    .iter()
+                    .filter(|coil| values.get(coil.as_str()) == Some(&true))
+                    .count();
+                if on > 1 {
+                    error(
+                        format!("scenes.{}", name),
+                        format!("more than one coil of interlocks.{} is switched on", group),
+                    );
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationErrors(errors))
+        }
+    }
+
+    /// The interlock group of a coil, if it is in one
+    pub fn interlock_of(&self, coil: &str) -> Option<(&str, &InterlockConfig)> {
+        self.interlocks
+            .iter()
+            .find(|(_, interlock)| interlock.coils.contains(coil))
+            .map(|(name, interlock)| (name.as_str(), interlock))
+    }
+
+    /// Delay between switching on two coils of a tag
+    pub fn switch_on_delay(&self, tag: &str) -> Duration {
+        let delay_ms = self
+            .tags
+            .get(tag)
+            .and_then(|tag| tag.switch_on_delay_ms)
+            .unwrap_or(self.switch_on_delay_ms);
+        Duration::from_millis(delay_ms)
+    }
+
+    /// The value of each coil which is switched by a scene
+    ///
+    /// The values of the coils override the values of their tags.
+    pub fn scene_values(&self, scene: &SceneConfig) -> BTreeMap<String, bool> {
+        let mut values = BTreeMap::new();
+        for (name, coil) in self.coils.iter() {
+            if let Some(value) = coil.tags.iter().find_map(|tag| scene.tags.get(tag)) {
+                values.insert(name.clone(), *value);
+            }
+        }
+        for (name, value) in scene.coils.iter() {
+            values.insert(name.clone(), *value);
+        }
+        values
+    }
+
+    /// Timeout of Modbus requests to a device
+    pub fn device_timeout(&self, device: &DeviceConfig) -> Duration {
+        match device.timeout_ms {
+            Some(timeout_ms) => Duration::from_millis(timeout_ms),
+            None => self.bus_timeout(&device.bus),
+        }
+    }
+}
+
+/// A semantic problem of the config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    /// TOML key of the problem, e.g. `coils.relay-1.address`
+    pub key: String,
+    pub message: String,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+/// All semantic problems of a config
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub struct ValidationErrors(pub Vec<ConfigError>);
+
+impl Display for ValidationErrors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "found {} problems in the config", self.0.len())?;
+        for error in self.0.iter() {
+            write!(f, "\n  {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Name of the bus which is used if a device does not name a bus
+pub const DEFAULT_BUS: &str = "default";
+
+/// Timeout of Modbus requests in milliseconds if the bus does not configure one
+pub const DEFAULT_TIMEOUT_MS: u64 = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(test, derive(JsonSchema))]
+pub struct BusConfig {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    /// URL of the bus, `rtu://<serial path>` or `tcp://<host>:<port>`
+    pub url: String,
+    /// Boud rate of a Modbus RTU bus
+    #[serde(default = "default_boud")]
+    pub boud: u32,
+    /// Timeout of Modbus requests on this bus in milliseconds
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_boud() -> u32 {
+    9600
+}
+
+fn default_timeout_ms() -> u64 {
+    DEFAULT_TIMEOUT_MS
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -19,8 +444,140 @@ pub struct DeviceConfig {
     #[serde(default)]
     #[serde(skip_serializing_if = "String::is_empty")]
     pub description: String,
+    /// Name of the bus the device is connected to
+    #[serde(default = "default_bus")]
+    pub bus: String,
     /// Address of the modbus device
     pub modbus_address: u8,
+    /// Timeout of Modbus requests to this device in milliseconds, replaces the timeout of the bus
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    /// Retry policy of the Modbus operations of this device, replaces the global retry policy
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryPolicy>,
+}
+
+fn default_bus() -> String {
+    DEFAULT_BUS.to_owned()
+}
+
+/// Retry policy of Modbus operations
+///
+/// Failed coil writes, coil reads and reads of the hardware version are retried
+/// with an exponential backoff.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(test, derive(JsonSchema))]
+pub struct RetryPolicy {
+    /// Number of attempts of an operation, including the first one
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+    /// Delay before the first retry in milliseconds, doubled for every further retry
+    #[serde(default = "default_backoff_ms")]
+    pub backoff_ms: u64,
+    /// Upper limit of the delay between two attempts in milliseconds
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    /// Errors after which an operation is retried
+    #[serde(default = "default_retry_on")]
+    pub retry_on: BTreeSet<RetryableError>,
+}
+
+impl RetryPolicy {
+    fn validate(&self, key: &str, error: &mut impl FnMut(String, String)) {
+        if self.attempts == 0 {
+            error(
+                format!("{}.attempts", key),
+                "at least one attempt is required".to_owned(),
+            );
+        }
+    }
+
+    /// Delay before the retry with the given number, starting at 0
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 1u64.checked_shl(retry).unwrap_or(u64::MAX);
+        Duration::from_millis(
+            self.backoff_ms
+                .saturating_mul(factor)
+                .min(self.max_backoff_ms),
+        )
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: default_attempts(),
+            backoff_ms: default_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+            retry_on: default_retry_on(),
+        }
+    }
+}
+
+fn default_attempts() -> u32 {
+    3
+}
+
+fn default_backoff_ms() -> u64 {
+    50
+}
+
+fn default_max_backoff_ms() -> u64 {
+    1000
+}
+
+fn default_retry_on() -> BTreeSet<RetryableError> {
+    [RetryableError::Timeout, RetryableError::Transport].into()
+}
+
+/// Kind of error after which a Modbus operation can be retried
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(test, derive(JsonSchema))]
+pub enum RetryableError {
+    /// The device did not answer in time
+    Timeout,
+    /// The answer was lost or corrupted, e.g. by a CRC error
+    Transport,
+    /// The device answered with a Modbus exception
+    Exception,
+}
+
+/// Circuit breaker of the devices
+///
+/// Requests to an offline device fail immediately instead of waiting for the timeout.
+/// Offline devices are probed in the background and are online again once they answer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(test, derive(JsonSchema))]
+pub struct CircuitBreakerConfig {
+    /// Number of consecutive failed Modbus operations after which a device is marked offline,
+    /// 0 disables the circuit breaker
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    /// Interval in milliseconds in which offline devices are probed
+    #[serde(default = "default_probe_interval_ms")]
+    pub probe_interval_ms: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failure_threshold: default_failure_threshold(),
+            probe_interval_ms: default_probe_interval_ms(),
+        }
+    }
+}
+
+fn default_failure_threshold() -> u32 {
+    3
+}
+
+fn default_probe_interval_ms() -> u64 {
+    5000
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -37,6 +594,218 @@ pub struct CoilConfig {
     pub default_status: ResetCoilStatus,
     #[serde(default)]
     pub tags: BTreeSet<String>,
+    /// How the coil is switched on
+    #[serde(default)]
+    pub mode: CoilMode,
+    /// Duration of a pulse in milliseconds, if no duration is requested
+    #[serde(default = "default_pulse_ms")]
+    pub pulse_ms: u64,
+    /// Time in milliseconds after which the coil is switched off automatically
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_on_time_ms: Option<u64>,
+    /// The load is connected to the normally closed contact, so it is on while the relais is off
+    ///
+    /// All values of the coil refer to the load, except the raw status.
+    #[serde(default)]
+    pub inverted: bool,
+}
+
+impl CoilConfig {
+    /// Value of the relais for a value of the load, and the other way round
+    pub fn relais_value(&self, value: bool) -> bool {
+        value != self.inverted
+    }
+
+    /// Time after which the coil is switched off automatically
+    pub fn max_on_time(&self) -> Option<Duration> {
+        self.max_on_time_ms.map(Duration::from_millis)
+    }
+}
+
+fn default_pulse_ms() -> u64 {
+    500
+}
+
+/// How a coil is switched on
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(test, derive(JsonSchema))]
+pub enum CoilMode {
+    /// The coil stays on until it is switched off
+    Normal,
+    /// Switching the coil on sends a pulse, e.g. for door openers and bells
+    Momentary,
+}
+
+impl Default for CoilMode {
+    fn default() -> Self {
+        CoilMode::Normal
+    }
+}
+
+/// Settings of the coils with a tag
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(test, derive(JsonSchema))]
+pub struct TagConfig {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    /// Delay in milliseconds between switching on two coils of the tag, replaces the global delay
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub switch_on_delay_ms: Option<u64>,
+    /// Coils which are switched on first, in this order, the other coils follow by name
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub switch_on_order: Vec<String>,
+}
+
+impl TagConfig {
+    /// Position of a coil in the switch-on order, coils which are not listed come last
+    pub fn switch_on_position(&self, coil: &str) -> usize {
+        self.switch_on_order
+            .iter()
+            .position(|name| name == coil)
+            .unwrap_or(usize::MAX)
+    }
+}
+
+/// A group of coils of which at most one may be on at a time
+///
+/// E.g. the "up" and "down" relais of a motor.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(test, derive(JsonSchema))]
+pub struct InterlockConfig {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    /// Names of the coils of the group
+    pub coils: BTreeSet<String>,
+    /// Minimum time in milliseconds between switching a coil of the group off
+    /// and switching another coil of the group on
+    #[serde(default)]
+    pub dead_time_ms: u64,
+    /// What happens if a coil should be switched on while another coil of the group is on
+    #[serde(default)]
+    pub policy: InterlockPolicy,
+}
+
+/// How a request which would switch on two coils of an interlock group is handled
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(test, derive(JsonSchema))]
+pub enum InterlockPolicy {
+    /// The request is rejected
+    Reject,
+    /// The other coils of the group are switched off first
+    SwitchOff,
+}
+
+impl Default for InterlockPolicy {
+    fn default() -> Self {
+        InterlockPolicy::Reject
+    }
+}
+
+/// An action which runs at fixed times, e.g. switching the outdoor lights on in the evening
+///
+/// Exactly one of `coil`, `tag` and `scene` has to be set.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(test, derive(JsonSchema))]
+pub struct ScheduleConfig {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    /// Cron expression of the run times, `minute hour day-of-month month day-of-week`,
+    /// optionally with a leading seconds and a trailing year field
+    pub cron: String,
+    /// IANA name of the timezone of the cron expression, e.g. `Europe/Berlin`,
+    /// the local timezone of the host if not given
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    /// Name of the coil which is switched
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coil: Option<String>,
+    /// Tag of the coils which are switched
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    /// Name of the scene which is activated
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scene: Option<String>,
+    /// Value the coil or the coils of the tag are switched to
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<bool>,
+    /// Priority of the bus commands
+    #[serde(default)]
+    pub priority: Priority,
+}
+
+impl ScheduleConfig {
+    /// The parsed cron expression
+    ///
+    /// Expressions without seconds run at the start of the minute.
+    pub fn cron_schedule(&self) -> anyhow::Result<cron::Schedule> {
+        let expression = if self.cron.split_whitespace().count() == 5 {
+            format!("0 {}", self.cron)
+        } else {
+            self.cron.clone()
+        };
+        cron::Schedule::from_str(&expression)
+            .map_err(|err| anyhow!("invalid cron expression {:?}: {}", self.cron, err))
+    }
+
+    /// The parsed timezone, `None` for the local timezone
+    pub fn timezone(&self) -> anyhow::Result<Option<Tz>> {
+        self.timezone
+            .as_deref()
+            .map(|timezone| Tz::from_str(timezone).map_err(|err| anyhow!(err)))
+            .transpose()
+    }
+
+    /// The first run time after `after`, in the timezone of the schedule
+    pub fn next_run(&self, after: &DateTime<Utc>) -> anyhow::Result<Option<DateTime<FixedOffset>>> {
+        let schedule = self.cron_schedule()?;
+        Ok(match self.timezone()? {
+            Some(timezone) => next_run_in(&schedule, after, &timezone),
+            None => next_run_in(&schedule, after, &Local),
+        })
+    }
+}
+
+fn next_run_in<Z: TimeZone>(
+    schedule: &cron::Schedule,
+    after: &DateTime<Utc>,
+    timezone: &Z,
+) -> Option<DateTime<FixedOffset>> {
+    schedule
+        .after(&after.with_timezone(timezone))
+        .next()
+        .map(|time| time.with_timezone(&time.offset().fix()))
+}
+
+/// A named preset of the values of several coils, e.g. a presentation mode
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(test, derive(JsonSchema))]
+pub struct SceneConfig {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    /// Values of single coils, these override the values of their tags
+    #[serde(default)]
+    pub coils: BTreeMap<String, bool>,
+    /// Values of all coils with a tag
+    #[serde(default)]
+    pub tags: BTreeMap<String, bool>,
 }
 
 /// Value to which a coil should be set if the coil/the device/the bus is resetted.
@@ -57,10 +826,184 @@ impl Default for ResetCoilStatus {
 
 #[cfg(test)]
 mod tests {
-    use crate::config::Config;
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+
+    use crate::config::{
+        Config, InterlockConfig, ResetCoilStatus, RetryPolicy, SceneConfig, ScheduleConfig,
+        TagConfig,
+    };
 
     #[test]
     fn parse_default_config() {
-        let _config: Config = toml::from_str(include_str!("../example-config.toml")).unwrap();
+        let config: Config = toml::from_str(include_str!("../example-config.toml")).unwrap();
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn validate_reports_all_problems() {
+        let mut config: Config = toml::from_str(include_str!("../example-config.toml")).unwrap();
+        config.devices.get_mut("relais-b").unwrap().modbus_address = 1;
+        let mut coil = config.coils["relay-1"].clone();
+        coil.tags.insert("relay-2".to_owned());
+        config.coils.insert("relay-9".to_owned(), coil);
+        config.retry.attempts = 0;
+        config.interlocks.insert(
+            "screen".to_owned(),
+            InterlockConfig {
+                coils: ["relay-1".to_owned(), "relay-2".to_owned()].into(),
+                ..Default::default()
+            },
+        );
+
+        let keys: Vec<_> = config
+            .validate()
+            .unwrap_err()
+            .0
+            .into_iter()
+            .map(|error| error.key)
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                "retry.attempts",
+                "devices.relais-b.modbus-address",
+                "coils.relay-9.address",
+                "coils.relay-9.tags",
+                "interlocks.screen.coils",
+            ]
+        );
+    }
+
+    #[test]
+    fn auto_off_coil_not_on_by_default() {
+        let mut config: Config = toml::from_str(include_str!("../example-config.toml")).unwrap();
+        let coil = config.coils.get_mut("relay-1").unwrap();
+        coil.max_on_time_ms = Some(60_000);
+        coil.default_status = ResetCoilStatus::On;
+
+        let keys: Vec<_> = config
+            .validate()
+            .unwrap_err()
+            .0
+            .into_iter()
+            .map(|error| error.key)
+            .collect();
+        assert_eq!(keys, vec!["coils.relay-1.default-status"]);
+    }
+
+    #[test]
+    fn coils_override_tags_in_scene() {
+        let config: Config = toml::from_str(include_str!("../example-config.toml")).unwrap();
+        let scene = SceneConfig {
+            coils: [("relay-2".to_owned(), false)].into(),
+            tags: [("first-half".to_owned(), true)].into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.scene_values(&scene),
+            [("relay-1".to_owned(), true), ("relay-2".to_owned(), false)].into()
+        );
+    }
+
+    #[test]
+    fn tag_delay_replaces_global_delay() {
+        let mut config: Config = toml::from_str(include_str!("../example-config.toml")).unwrap();
+        config.switch_on_delay_ms = 200;
+        config.tags.insert(
+            "first-half".to_owned(),
+            TagConfig {
+                switch_on_delay_ms: Some(1000),
+                switch_on_order: vec!["relay-2".to_owned()],
+                ..Default::default()
+            },
+        );
+        config.validate().unwrap();
+        assert_eq!(config.switch_on_delay("first-half"), Duration::from_secs(1));
+        assert_eq!(config.switch_on_delay("other"), Duration::from_millis(200));
+        assert_eq!(config.tags["first-half"].switch_on_position("relay-2"), 0);
+    }
+
+    #[test]
+    fn schedule_of_scene() {
+        let mut config: Config = toml::from_str(include_str!("../example-config.toml")).unwrap();
+        config.scenes.insert(
+            "evening".to_owned(),
+            SceneConfig {
+                tags: [("first-half".to_owned(), true)].into(),
+                ..Default::default()
+            },
+        );
+        let schedule = ScheduleConfig {
+            cron: "0 18 * * *".to_owned(),
+            scene: Some("evening".to_owned()),
+            ..Default::default()
+        };
+        config
+            .schedules
+            .insert("evening".to_owned(), schedule.clone());
+        config.validate().unwrap();
+
+        config.schedules.insert(
+            "both".to_owned(),
+            ScheduleConfig {
+                tag: Some("first-half".to_owned()),
+                value: Some(true),
+                ..schedule.clone()
+            },
+        );
+        config.schedules.insert(
+            "missing".to_owned(),
+            ScheduleConfig {
+                scene: Some("night".to_owned()),
+                ..schedule
+            },
+        );
+        let keys: Vec<_> = config
+            .validate()
+            .unwrap_err()
+            .0
+            .into_iter()
+            .map(|error| error.key)
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                "schedules.both",
+                "schedules.both.value",
+                "schedules.missing.scene"
+            ]
+        );
+    }
+
+    #[test]
+    fn next_run_of_schedule() {
+        let schedule = ScheduleConfig {
+            cron: "30 18 * * Mon-Fri".to_owned(),
+            timezone: Some("Europe/Berlin".to_owned()),
+            ..Default::default()
+        };
+        // a saturday in summer time
+        let after = Utc.with_ymd_and_hms(2022, 7, 2, 12, 0, 0).unwrap();
+        let next = schedule.next_run(&after).unwrap().unwrap();
+        assert_eq!(next.to_rfc3339(), "2022-07-04T18:30:00+02:00");
+
+        let invalid = ScheduleConfig {
+            cron: "every day".to_owned(),
+            timezone: Some("Mars/Olympus".to_owned()),
+            ..Default::default()
+        };
+        assert!(invalid.cron_schedule().is_err());
+        assert!(invalid.timezone().is_err());
+    }
+
+    #[test]
+    fn retry_backoff_is_limited() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.delay(0), Duration::from_millis(50));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(40), Duration::from_millis(1000));
+        assert_eq!(policy.delay(100), Duration::from_millis(1000));
     }
 }
@@ -0,0 +1,405 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use tracing::{info, instrument};
+
+use crate::config::{
+    BusConfig, CoilConfig, Config, DeviceConfig, InterlockConfig, SceneConfig, ScheduleConfig,
+    TagConfig,
+};
+
+/// File format of a config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// The format of a config file by its extension
+    pub fn from_path(path: &Path) -> anyhow::Result<ConfigFormat> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Ok(ConfigFormat::Toml),
+            Some("yaml" | "yml") => Ok(ConfigFormat::Yaml),
+            Some("json") => Ok(ConfigFormat::Json),
+            _ => bail!(
+                "unknown format of the config file {}, expected a .toml, .yaml, .yml or .json file",
+                path.display()
+            ),
+        }
+    }
+
+    /// Parse a config file of this format
+    pub fn parse<T: for<'de> Deserialize<'de>>(self, content: &str) -> anyhow::Result<T> {
+        Ok(match self {
+            ConfigFormat::Toml => toml::from_str(content)?,
+            ConfigFormat::Yaml => serde_yaml::from_str(content)?,
+            ConfigFormat::Json => serde_json::from_str(content)?,
+        })
+    }
+}
+
+impl FromStr for ConfigFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "toml" => Ok(ConfigFormat::Toml),
+            "yaml" => Ok(ConfigFormat::Yaml),
+            "json" => Ok(ConfigFormat::Json),
+            other => Err(anyhow::Error::msg(format!(
+                "unknown config format {:?}, expected \"toml\", \"yaml\" or \"json\"",
+                other
+            ))),
+        }
+    }
+}
+
+/// Patterns of the config files in a config directory
+const CONFIG_DIRECTORY_PATTERNS: [&str; 4] = ["*.toml", "*.yaml", "*.yml", "*.json"];
+
+/// Buses, devices, coils, tags, interlocks, schedules and scenes of an included config file
+///
+/// Global settings, e.g. the retry policy, the circuit breaker or the switch-on delay,
+/// can only be set in the main config file, an included file which sets them is rejected.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ConfigFragment {
+    #[serde(default)]
+    buses: BTreeMap<String, BusConfig>,
+    #[serde(default)]
+    devices: BTreeMap<String, DeviceConfig>,
+    #[serde(default)]
+    coils: BTreeMap<String, CoilConfig>,
+    #[serde(default)]
+    tags: BTreeMap<String, TagConfig>,
+    #[serde(default)]
+    interlocks: BTreeMap<String, InterlockConfig>,
+    #[serde(default)]
+    schedules: BTreeMap<String, ScheduleConfig>,
+    #[serde(default)]
+    scenes: BTreeMap<String, SceneConfig>,
+}
+
+/// Load and validate a config file together with all files it includes
+///
+/// The format of the config file is picked by its extension, unless a `format` is given.
+/// Included files always use the format of their extension.
+/// If `path` is a directory, all config files in it are loaded like included files,
+/// so the global settings keep their defaults.
+#[instrument]
+pub async fn load_config(path: &str, format: Option<ConfigFormat>) -> anyhow::Result<Config> {
+    let path = Path::new(path);
+    let mut config = if path.is_dir() {
+        Config {
+            include: CONFIG_DIRECTORY_PATTERNS.map(str::to_owned).into(),
+            ..Default::default()
+        }
+    } else {
+        read_config_file(path, format).await?
+    };
+
+    // the file each key was first defined in
+    let mut sources: BTreeMap<String, PathBuf> = BTreeMap::new();
+    let mut errors = Vec::new();
+    let fragment = ConfigFragment {
+        buses: std::mem::take(&mut config.buses),
+        devices: std::mem::take(&mut config.devices),
+        coils: std::mem::take(&mut config.coils),
+        tags: std::mem::take(&mut config.tags),
+        interlocks: std::mem::take(&mut config.interlocks),
+        schedules: std::mem::take(&mut config.schedules),
+        scenes: std::mem::take(&mut config.scenes),
+    };
+    merge(&mut config, fragment, path, &mut sources, &mut errors);
+
+    for file in included_files(path, &config)? {
+        let fragment: ConfigFragment = read_config_file(&file, None).await?;
+        merge(&mut config, fragment, &file, &mut sources, &mut errors);
+    }
+    if !errors.is_empty() {
+        bail!("duplicate keys in the config\n  {}", errors.join("\n  "));
+    }
+
+    config.validate()?;
+    info!(device_count = config.devices.len(), "read config");
+    Ok(config)
+}
+
+async fn read_config_file<T: for<'de> Deserialize<'de>>(
+    path: &Path,
+    format: Option<ConfigFormat>,
+) -> anyhow::Result<T> {
+    let format = match format {
+        Some(format) => format,
+        None => ConfigFormat::from_path(path)?,
+    };
+    let config_string = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Error reading the config file {}", path.display()))?;
+    format
+        .parse(&config_string)
+        .with_context(|| format!("Error parsing the config file {}", path.display()))
+}
+
+/// The files which are included by a config, in the order they are merged
+pub fn included_files(path: &Path, config: &Config) -> anyhow::Result<Vec<PathBuf>> {
+    let base_dir = if path.is_dir() {
+        path
+    } else {
+        path.parent().unwrap_or_else(|| Path::new(""))
+    };
+
+    let mut files = Vec::new();
+    for pattern in config.include.iter() {
+        let pattern_path = base_dir.join(pattern);
+        let pattern_str = pattern_path
+            .to_str()
+            .with_context(|| format!("Include pattern {:?} is not valid UTF-8", pattern))?;
+        let mut matches = glob::glob(pattern_str)
+            .with_context(|| format!("Invalid include pattern {:?}", pattern))?
+            .collect::<Result<Vec<_>, _>>()?;
+        matches.sort();
+        for file in matches {
+            if file != path && !files.contains(&file) {
+                files.push(file);
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn merge(
+    config: &mut Config,
+    fragment: ConfigFragment,
+    file: &Path,
+    sources: &mut BTreeMap<String, PathBuf>,
+    errors: &mut Vec<String>,
+) {
+    merge_section(
+        "buses",
+        &mut config.buses,
+        fragment.buses,
+        file,
+        sources,
+        errors,
+    );
+    merge_section(
+        "devices",
+        &mut config.devices,
+        fragment.devices,
+        file,
+        sources,
+        errors,
+    );
+    merge_section(
+        "coils",
+        &mut config.coils,
+        fragment.coils,
+        file,
+        sources,
+        errors,
+    );
+    merge_section(
+        "tags",
+        &mut config.tags,
+        fragment.tags,
+        file,
+        sources,
+        errors,
+    );
+    merge_section(
+        "interlocks",
+        &mut config.interlocks,
+        fragment.interlocks,
+        file,
+        sources,
+        errors,
+    );
+    merge_section(
+        "schedules",
+        &mut config.schedules,
+        fragment.schedules,
+        file,
+        sources,
+        errors,
+    );
+    merge_section(
+        "scenes",
+        &mut config.scenes,
+        fragment.scenes,
+        file,
+        sources,
+        errors,
+    );
+}
+
+fn merge_section<T>(
+    section: &str,
+    target: &mut BTreeMap<String, T>,
+    entries: BTreeMap<String, T>,
+    file: &Path,
+    sources: &mut BTreeMap<String, PathBuf>,
+    errors: &mut Vec<String>,
+) {
+    for (name, value) in entries {
+        let key = format!("{}.{}", section, name);
+        match sources.get(&key) {
+            Some(first) => errors.push(format!(
+                "{} is defined in {} and {}",
+                key,
+                first.display(),
+                file.display()
+            )),
+            None => {
+                sources.insert(key, file.to_owned());
+                target.insert(name, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{load_config, ConfigFormat};
+    use crate::config::Config;
+
+    fn write(dir: &std::path::Path, name: &str, content: &str) {
+        fs::write(dir.join(name), content).unwrap();
+    }
+
+    #[tokio::test]
+    async fn merge_included_files() {
+        let dir = std::env::temp_dir().join(format!("dorfbusd-include-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+
+        write(
+            &dir,
+            "config.toml",
+            "include = [\"conf.d/*.toml\"]\n[devices.relais-a]\nmodbus-address = 1\n",
+        );
+        write(
+            &dir.join("conf.d"),
+            "a.toml",
+            "[coils.relay-1]\ndevice = \"relais-a\"\naddress = 0\ndefault-status = \"on\"\n",
+        );
+        write(
+            &dir.join("conf.d"),
+            "b.toml",
+            "[devices.relais-b]\nmodbus-address = 2\n",
+        );
+        let config = load_config(dir.join("config.toml").to_str().unwrap(), None)
+            .await
+            .unwrap();
+        assert_eq!(config.devices.len(), 2);
+        assert_eq!(config.coils.len(), 1);
+
+        write(
+            &dir.join("conf.d"),
+            "c.toml",
+            "[devices.relais-a]\nmodbus-address = 3\n",
+        );
+        let err = load_config(dir.join("config.toml").to_str().unwrap(), None)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("devices.relais-a is defined in"), "{}", err);
+        assert!(err.contains("config.toml and"), "{}", err);
+        assert!(err.contains("c.toml"), "{}", err);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reject_global_settings_in_included_files() {
+        let dir = std::env::temp_dir().join(format!("dorfbusd-global-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+
+        write(&dir, "config.toml", "include = [\"conf.d/*.toml\"]\n");
+        write(
+            &dir.join("conf.d"),
+            "a.toml",
+            "switch-on-delay-ms = 200\n[devices.relais-a]\nmodbus-address = 1\n",
+        );
+        let err = load_config(dir.join("config.toml").to_str().unwrap(), None)
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("switch-on-delay-ms"),
+            "{:#}",
+            err
+        );
+
+        // all files of a config directory are included files
+        let err = load_config(dir.join("conf.d").to_str().unwrap(), None)
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("switch-on-delay-ms"),
+            "{:#}",
+            err
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn example_configs_are_equal() {
+        let toml: Config = ConfigFormat::Toml
+            .parse(include_str!("../example-config.toml"))
+            .unwrap();
+        let yaml: Config = ConfigFormat::Yaml
+            .parse(include_str!("../example-config.yaml"))
+            .unwrap();
+        let json: Config = ConfigFormat::Json
+            .parse(include_str!("../example-config.json"))
+            .unwrap();
+
+        assert_eq!(toml, yaml);
+        assert_eq!(toml, json);
+        yaml.validate().unwrap();
+        json.validate().unwrap();
+    }
+
+    #[test]
+    fn full_configs_are_equal() {
+        let toml: Config = ConfigFormat::Toml
+            .parse(include_str!("../testdata/full-config.toml"))
+            .unwrap();
+        let yaml: Config = ConfigFormat::Yaml
+            .parse(include_str!("../testdata/full-config.yaml"))
+            .unwrap();
+        let json: Config = ConfigFormat::Json
+            .parse(include_str!("../testdata/full-config.json"))
+            .unwrap();
+
+        assert_eq!(toml, yaml);
+        assert_eq!(toml, json);
+        toml.validate().unwrap();
+
+        // unknown keys are ignored, so every key of the fixture has to survive a round trip
+        fn assert_known_keys(fixture: &serde_json::Value, parsed: &serde_json::Value, path: &str) {
+            if let Some(fixture) = fixture.as_object() {
+                for (key, value) in fixture {
+                    let path = format!("{}.{}", path, key);
+                    let parsed = parsed.get(key);
+                    assert!(parsed.is_some(), "unknown key {}", path);
+                    assert_known_keys(value, parsed.unwrap(), &path);
+                }
+            }
+        }
+        let fixture: serde_json::Value =
+            serde_json::from_str(include_str!("../testdata/full-config.json")).unwrap();
+        assert_known_keys(&fixture, &serde_json::to_value(&json).unwrap(), "");
+    }
+}
@@ -0,0 +1,71 @@
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Mutex as TokioMutex;
+
+/// Runtime state of an interlock group
+///
+/// The state is kept by the name of the group, so it survives config reloads.
+#[derive(Debug, Default)]
+pub struct Interlock {
+    /// Held while a coil of the group is switched,
+    /// so the states of the other coils can not change in between
+    pub lock: TokioMutex<()>,
+    /// When the coils of the group were switched off the last time
+    switched_off: Mutex<BTreeMap<String, Instant>>,
+}
+
+impl Interlock {
+    /// Remember that a coil of the group was switched off
+    pub fn record_off(&self, coil: &str) {
+        self.switched_off
+            .lock()
+            .unwrap()
+            .insert(coil.to_owned(), Instant::now());
+    }
+
+    /// Time to wait before `coil` may be switched on
+    ///
+    /// The dead time starts when another coil of the group was switched off.
+    pub fn remaining_dead_time(&self, coil: &str, dead_time: Duration) -> Duration {
+        self.switched_off
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(name, _)| name.as_str() != coil)
+            .map(|(_, switched_off)| dead_time.saturating_sub(switched_off.elapsed()))
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Interlock;
+
+    #[test]
+    fn dead_time_after_other_coil() {
+        let interlock = Interlock::default();
+        let dead_time = Duration::from_secs(60);
+        assert_eq!(
+            interlock.remaining_dead_time("up", dead_time),
+            Duration::ZERO
+        );
+
+        interlock.record_off("down");
+        assert_eq!(
+            interlock.remaining_dead_time("down", dead_time),
+            Duration::ZERO
+        );
+        assert!(interlock.remaining_dead_time("up", dead_time) > Duration::from_secs(59));
+        assert_eq!(
+            interlock.remaining_dead_time("up", Duration::ZERO),
+            Duration::ZERO
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::{bus_state::CoilUpdate, state::StateResult};
+
+/// Number of finished jobs whose status is kept
+const KEPT_JOBS: usize = 100;
+
+/// State of a job
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum JobState {
+    /// The coils are still being switched
+    Running,
+    /// All coils were switched
+    Finished,
+    /// Switching a coil failed
+    Failed,
+}
+
+/// Status of a job which switches coils in the background
+#[derive(Serialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub struct JobStatus {
+    /// Id of the job
+    pub id: u64,
+    pub state: JobState,
+    /// Status of the coils, once the job is finished
+    pub result: Option<Vec<CoilUpdate>>,
+    /// Error of the job, if it failed
+    pub error: Option<String>,
+}
+
+/// Status of the recent jobs
+///
+/// Only the last finished jobs are kept, running jobs are always kept.
+#[derive(Debug, Default)]
+pub struct Jobs {
+    next_id: AtomicU64,
+    jobs: Mutex<BTreeMap<u64, JobStatus>>,
+}
+
+impl Jobs {
+    /// Register a new running job
+    pub fn start(&self) -> JobStatus {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let status = JobStatus {
+            id,
+            state: JobState::Running,
+            result: None,
+            error: None,
+        };
+        self.jobs.lock().unwrap().insert(id, status.clone());
+        status
+    }
+
+    /// Record the result of a job
+    pub fn finish(&self, id: u64, result: StateResult<Vec<CoilUpdate>>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(status) = jobs.get_mut(&id) {
+            match result {
+                Ok(coil_updates) => {
+                    status.state = JobState::Finished;
+                    status.result = Some(coil_updates);
+                }
+                Err(err) => {
+                    status.state = JobState::Failed;
+                    status.error = Some(err.to_string());
+                }
+            }
+        }
+
+        let finished: Vec<u64> = jobs
+            .values()
+            .filter(|status| status.state != JobState::Running)
+            .map(|status| status.id)
+            .collect();
+        for id in finished
+            .iter()
+            .take(finished.len().saturating_sub(KEPT_JOBS))
+        {
+            jobs.remove(id);
+        }
+    }
+
+    pub fn status(&self, id: u64) -> Option<JobStatus> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JobState, Jobs, KEPT_JOBS};
+    use crate::state::StateError;
+
+    #[test]
+    fn only_recent_finished_jobs_are_kept() {
+        let jobs = Jobs::default();
+        let running = jobs.start();
+        let first = jobs.start();
+        jobs.finish(first.id, Err(StateError::TagNotFound("all".to_owned())));
+        let status = jobs.status(first.id).unwrap();
+        assert_eq!(status.state, JobState::Failed);
+        assert_eq!(status.error.as_deref(), Some("tag \"all\" not found"));
+
+        for _ in 0..KEPT_JOBS {
+            let job = jobs.start();
+            jobs.finish(job.id, Ok(Vec::new()));
+        }
+        assert!(jobs.status(first.id).is_none());
+        assert_eq!(jobs.status(running.id).unwrap().state, JobState::Running);
+    }
+}
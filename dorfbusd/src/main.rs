@@ -1,4 +1,4 @@
-use std::{net::SocketAddr, str::FromStr};
+use std::{collections::BTreeMap, net::SocketAddr, path::Path, str::FromStr};
 
 use anyhow::Context;
 use axum::{
@@ -9,26 +9,38 @@ use axum::{
     AddExtensionLayer, Router,
 };
 use clap::{crate_authors, crate_name, crate_version};
-use config::Config;
 use http::{Method, StatusCode, Uri};
-use tokio::{fs::File, io::AsyncReadExt};
-use tokio_modbus::client::rtu;
-use tokio_serial::SerialStream;
 use tower::ServiceBuilder;
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
-use tracing::{error, info, instrument, warn};
+use tracing::{info, warn};
 
-use crate::{api::api_routes, state::State};
+use crate::{
+    api::api_routes, config_loader::load_config, persistence::StateStore,
+    simulation::simulated_buses, state::State, transport::bus_transports,
+};
 
 mod api;
+mod auto_off;
+mod bus_queue;
 mod bus_state;
 mod cli;
 mod config;
+mod config_loader;
+mod interlock;
+mod jobs;
 mod model;
+mod persistence;
+mod poller;
+mod reload;
+mod retry;
+mod scene;
+mod scheduler;
+mod simulation;
 mod state;
 mod swagger_ui;
 #[cfg(test)]
 mod tests;
+mod transport;
 
 async fn default_404(method: Method, original_uri: OriginalUri) -> impl IntoResponse {
     warn!(
@@ -43,24 +55,6 @@ async fn default_404(method: Method, original_uri: OriginalUri) -> impl IntoResp
     )
 }
 
-#[instrument]
-async fn load_config(path: &str) -> anyhow::Result<Config> {
-    let mut config_string = String::new();
-    File::open(path)
-        .await
-        .with_context(|| "Error opening the config file")?
-        .read_to_string(&mut config_string)
-        .await
-        .with_context(|| "Error reading the config file")?;
-    match toml::from_str::<Config>(&config_string) {
-        Ok(config) => {
-            info!(device_count = config.devices.len(), "read config");
-            Ok(config)
-        }
-        Err(err) => Err(err.into()),
-    }
-}
-
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
@@ -68,9 +62,22 @@ async fn main() -> anyhow::Result<()> {
     let params =
         cli::app().map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
 
+    if params.check_config {
+        let config = load_config(&params.config_path, params.config_format)
+            .await
+            .with_context(|| format!("Invalid config {}", params.config_path))?;
+        println!(
+            "config {} is valid ({} devices, {} coils)",
+            params.config_path,
+            config.devices.len(),
+            config.coils.len()
+        );
+        return Ok(());
+    }
+
     info!(include_str!("motd.txt"));
 
-    let config = load_config(&params.config_path)
+    let config = load_config(&params.config_path, params.config_format)
         .await
         .context("Could not load config")?;
 
@@ -78,27 +85,47 @@ async fn main() -> anyhow::Result<()> {
         version = crate_version!(),
         authors = crate_authors!(),
         http_port = %params.port,
-        serial_path = %params.serial_path,
-        serial_boud = %params.serial_boud,
+        poll_interval = ?params.poll_interval,
+        request_timeout = ?params.request_timeout,
+        state_path = ?params.state_path,
+        restore_policy = ?params.restore_policy,
+        simulate = params.simulate,
         "starting {}",
         crate_name!()
     );
 
-    let builder = tokio_serial::new(&params.serial_path, params.serial_boud);
-    let port = SerialStream::open(&builder).context("Could not open the serial device")?;
-
-    let modbus_ctx = rtu::connect(port).await?;
-
-    let state = State::new(params.clone(), config, modbus_ctx)?;
-
-    {
-        let state = state.clone();
-        let _join_handle = tokio::spawn(async move {
-            if let Err(err) = state.bus_state().check_state_from_device(&state).await {
-                error!("{:?}", err);
-            }
-        });
-    }
+    let buses = if params.simulate {
+        warn!("simulating the configured devices, no bus is used");
+        simulated_buses(&config)
+    } else {
+        let mut buses = BTreeMap::new();
+        for (name, transport) in bus_transports(&params, &config)? {
+            info!(bus = %name, %transport, "connecting to bus");
+            let modbus_ctx = transport
+                .connect()
+                .await
+                .with_context(|| format!("Could not connect to bus {}", name))?;
+            buses.insert(name, modbus_ctx);
+        }
+        buses
+    };
+
+    let state_store = match &params.state_path {
+        Some(path) => Some(
+            StateStore::load(Path::new(path), params.restore_policy)
+                .await
+                .context("Could not load the state file")?,
+        ),
+        None => None,
+    };
+
+    let state = State::new(params.clone(), config, buses, state_store)?;
+
+    let _poller_handle = tokio::spawn(poller::poll_bus(state.clone()));
+    let _probe_handle = tokio::spawn(poller::probe_offline_devices(state.clone()));
+    let _reload_handle = tokio::spawn(reload::watch_config(state.clone()));
+    let _scheduler_handle = tokio::spawn(scheduler::run_schedules(state.clone()));
+    let _auto_off_handle = tokio::spawn(auto_off::run_auto_off(state.clone()));
 
     let cors = CorsLayer::permissive();
 
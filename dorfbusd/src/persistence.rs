@@ -0,0 +1,262 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex as TokioMutex};
+use tracing::{info, instrument, warn};
+
+/// What to do with the persisted coil values on startup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestorePolicy {
+    /// Write the persisted values to the devices when they appear on the bus.
+    ///
+    /// The persisted value of a coil takes precedence over its default status.
+    Restore,
+    /// Only use the persisted values as the initial coil status.
+    Seed,
+}
+
+impl FromStr for RestorePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "restore" => Ok(RestorePolicy::Restore),
+            "seed" => Ok(RestorePolicy::Seed),
+            other => Err(anyhow::Error::msg(format!(
+                "unknown restore policy {:?}, expected \"restore\" or \"seed\"",
+                other
+            ))),
+        }
+    }
+}
+
+/// Content of the state file
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct PersistentState {
+    /// Last desired value of each coil
+    #[serde(default)]
+    pub coils: BTreeMap<String, bool>,
+    /// Names of the paused schedules
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    pub paused_schedules: BTreeSet<String>,
+    /// Unix timestamps in milliseconds at which coils are switched off automatically
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub auto_off: BTreeMap<String, u64>,
+}
+
+/// On-disk store of the last desired coil values, the auto-off timers and the paused schedules
+#[derive(Debug)]
+pub struct StateStore {
+    path: PathBuf,
+    policy: RestorePolicy,
+    state: Mutex<PersistentState>,
+    /// Serializes writes of the state file
+    write_lock: TokioMutex<()>,
+}
+
+impl StateStore {
+    /// Load the state file
+    ///
+    /// A missing state file is treated as an empty state.
+    #[instrument]
+    pub async fn load(path: &Path, policy: RestorePolicy) -> anyhow::Result<StateStore> {
+        let state = match fs::read_to_string(path).await {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("Error parsing the state file {:?}", path))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                info!("state file does not exist yet");
+                PersistentState::default()
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("Error reading the state file {:?}", path))
+            }
+        };
+
+        Ok(StateStore {
+            path: path.to_owned(),
+            policy,
+            state: Mutex::new(state),
+            write_lock: TokioMutex::new(()),
+        })
+    }
+
+    pub fn policy(&self) -> RestorePolicy {
+        self.policy
+    }
+
+    /// Last desired value of a coil
+    pub fn coil_value(&self, name: &str) -> Option<bool> {
+        self.state.lock().unwrap().coils.get(name).copied()
+    }
+
+    /// Record the desired values and the auto-off timers of several coils
+    /// and write the state file once
+    ///
+    /// Errors are logged, as a failing state file should not fail the coil update.
+    pub async fn set_coil_values(&self, coils: &[(&str, bool, Option<SystemTime>)]) {
+        self.update(|state| {
+            let mut changed = false;
+            for (name, value, off_at) in coils {
+                changed |= state.coils.insert((*name).to_owned(), *value) != Some(*value);
+                changed |= set_timestamp(&mut state.auto_off, name, *off_at);
+            }
+            changed
+        })
+        .await;
+    }
+
+    /// Names of the paused schedules
+    pub fn paused_schedules(&self) -> BTreeSet<String> {
+        self.state.lock().unwrap().paused_schedules.clone()
+    }
+
+    /// Record whether a schedule is paused and write the state file
+    pub async fn set_schedule_paused(&self, name: &str, paused: bool) {
+        self.update(|state| {
+            if paused {
+                state.paused_schedules.insert(name.to_owned())
+            } else {
+                state.paused_schedules.remove(name)
+            }
+        })
+        .await;
+    }
+
+    /// Time at which a coil is switched off automatically
+    pub fn auto_off(&self, name: &str) -> Option<SystemTime> {
+        let timestamp_ms = *self.state.lock().unwrap().auto_off.get(name)?;
+        Some(UNIX_EPOCH + Duration::from_millis(timestamp_ms))
+    }
+
+    /// Record the auto-off timer of a coil and write the state file
+    pub async fn set_auto_off(&self, name: &str, off_at: Option<SystemTime>) {
+        self.update(|state| set_timestamp(&mut state.auto_off, name, off_at))
+            .await;
+    }
+
+    /// Record the auto-off timers of several coils and write the state file once, if they changed
+    pub async fn set_auto_offs(&self, timers: &[(&str, Option<SystemTime>)]) {
+        self.update(|state| {
+            let mut changed = false;
+            for (name, off_at) in timers {
+                changed |= set_timestamp(&mut state.auto_off, name, *off_at);
+            }
+            changed
+        })
+        .await;
+    }
+
+    /// Change the state and write the state file, if `update` reports a change
+    async fn update(&self, update: impl FnOnce(&mut PersistentState) -> bool) {
+        let _write_guard = self.write_lock.lock().await;
+
+        let content = {
+            let mut state = self.state.lock().unwrap();
+            if !update(&mut state) {
+                return;
+            }
+            serde_json::to_string_pretty(&*state)
+        };
+
+        let res = match content {
+            Ok(content) => self.write(content.as_bytes()).await,
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = res {
+            warn!(path = ?self.path, %err, "could not write state file");
+        }
+    }
+
+    /// Atomically replace the state file
+    async fn write(&self, content: &[u8]) -> std::io::Result<()> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+
+        let mut file = fs::File::create(&tmp_path).await?;
+        file.write_all(content).await?;
+        file.sync_all().await?;
+        drop(file);
+
+        fs::rename(&tmp_path, &self.path).await
+    }
+}
+
+/// Set or remove the timestamp of `name` in unix milliseconds, returns whether this changed anything
+fn set_timestamp(
+    timestamps: &mut BTreeMap<String, u64>,
+    name: &str,
+    time: Option<SystemTime>,
+) -> bool {
+    match time {
+        Some(time) => {
+            let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let timestamp_ms = u64::try_from(since_epoch.as_millis()).unwrap_or(u64::MAX);
+            timestamps.insert(name.to_owned(), timestamp_ms) != Some(timestamp_ms)
+        }
+        None => timestamps.remove(name).is_some(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{PersistentState, RestorePolicy, StateStore};
+
+    #[test]
+    fn parse_restore_policy() {
+        assert_eq!(
+            "restore".parse::<RestorePolicy>().unwrap(),
+            RestorePolicy::Restore
+        );
+        assert_eq!(
+            "seed".parse::<RestorePolicy>().unwrap(),
+            RestorePolicy::Seed
+        );
+        assert!("something".parse::<RestorePolicy>().is_err());
+    }
+
+    #[test]
+    fn parse_state_file() {
+        let state: PersistentState =
+            serde_json::from_str(r#"{"coils": {"relay-1": true, "relay-2": false}}"#).unwrap();
+        assert_eq!(state.coils.get("relay-1"), Some(&true));
+        assert_eq!(state.coils.get("relay-2"), Some(&false));
+        assert!(state.auto_off.is_empty());
+    }
+    #[tokio::test]
+    async fn write_and_load_state_file() {
+        let dir = std::env::temp_dir().join(format!("dorfbusd-state-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+        let _ = std::fs::remove_file(&path);
+        let off_at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_000);
+
+        let store = StateStore::load(&path, RestorePolicy::Restore)
+            .await
+            .unwrap();
+        store
+            .set_coil_values(&[("relay-1", true, Some(off_at)), ("relay-2", false, None)])
+            .await;
+        store.set_schedule_paused("lights-on", true).await;
+        assert!(!dir.join("state.json.tmp").exists());
+
+        let loaded = StateStore::load(&path, RestorePolicy::Seed).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(loaded.coil_value("relay-1"), Some(true));
+        assert_eq!(loaded.coil_value("relay-2"), Some(false));
+        assert_eq!(loaded.auto_off("relay-1"), Some(off_at));
+        assert_eq!(loaded.auto_off("relay-2"), None);
+        assert!(loaded.paused_schedules().contains("lights-on"));
+    }
+}
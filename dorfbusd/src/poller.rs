@@ -0,0 +1,40 @@
+use std::time::Duration;
+
+use tokio::time::{self, MissedTickBehavior};
+use tracing::{info, instrument};
+
+use crate::state::State;
+
+/// Poll all devices on the bus in the configured interval
+///
+/// This keeps the hardware versions, the seen flags and the coil states of the devices
+/// up to date, e.g. if a device is powered off or added later.
+#[instrument(skip_all)]
+pub async fn poll_bus(state: State) {
+    let mut interval = time::interval(state.params().poll_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    // the first tick completes immediately
+    interval.tick().await;
+    info!("reading initial state of the bus");
+    state.reset_bus_state().await;
+
+    loop {
+        interval.tick().await;
+        state.bus_state().check_state_from_device(&state).await;
+    }
+}
+
+/// Probe the offline devices in the configured interval
+///
+/// The devices which answer again are marked online by their circuit breaker.
+/// The interval is read from the current config before every wait, so a reload changes it.
+#[instrument(skip_all)]
+pub async fn probe_offline_devices(state: State) {
+    loop {
+        let probe_interval =
+            Duration::from_millis(state.config().circuit_breaker.probe_interval_ms);
+        time::sleep(probe_interval).await;
+        state.bus_state().probe_offline_devices(&state).await;
+    }
+}
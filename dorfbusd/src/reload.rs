@@ -0,0 +1,188 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use tokio::{
+    signal::unix::{signal, Signal, SignalKind},
+    time,
+};
+use tracing::{info, instrument, warn};
+
+use crate::{
+    config::Config,
+    config_loader::{included_files, load_config},
+    state::State,
+};
+
+/// Interval in which the config file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Reload the config on SIGHUP and whenever the config file or an included file changes
+#[instrument(skip_all)]
+pub async fn watch_config(state: State) {
+    let mut hangup = signal(SignalKind::hangup())
+        .map_err(|err| warn!(%err, "could not listen for SIGHUP"))
+        .ok();
+
+    let path = state.params().config_path.clone();
+    let mut modified = modified_times(&path, &state.config()).await;
+
+    loop {
+        // the config file is checked whenever no signal arrived within the interval
+        match time::timeout(WATCH_INTERVAL, recv_signal(&mut hangup)).await {
+            Ok(()) => info!("received SIGHUP"),
+            Err(_) => {
+                if modified_times(&path, &state.config()).await == modified {
+                    continue;
+                }
+                info!("config file changed");
+            }
+        }
+
+        reload_config(&state).await;
+        modified = modified_times(&path, &state.config()).await;
+    }
+}
+
+/// Wait for a signal, forever if the signal could not be registered
+async fn recv_signal(signal: &mut Option<Signal>) {
+    match signal {
+        Some(signal) => {
+            signal.recv().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Modification times of the config file and all included files
+async fn modified_times(path: &str, config: &Config) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let path = Path::new(path);
+    let mut files = vec![path.to_owned()];
+    files.extend(included_files(path, config).unwrap_or_default());
+
+    let mut modified = Vec::new();
+    for file in files {
+        let time = tokio::fs::metadata(&file)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        modified.push((file, time));
+    }
+    modified
+}
+
+/// Load the config file again and replace the config of the state
+///
+/// An invalid config is logged and the old config stays in use.
+pub async fn reload_config(state: &State) {
+    let params = state.params();
+    let config = match load_config(&params.config_path, params.config_format).await {
+        Ok(config) => config,
+        Err(err) => {
+            warn!(
+                event = "config-reload-failed",
+                err = %format!("{:#}", err),
+                "could not load config, keeping the old config"
+            );
+            return;
+        }
+    };
+
+    let changes = config_diff(&state.config(), &config);
+    if changes.is_empty() {
+        info!("config did not change");
+        return;
+    }
+
+    if let Err(err) = state.reload_config(config) {
+        warn!(
+            event = "config-reload-failed",
+            err = %format!("{:#}", err),
+            "rejected config, keeping the old config"
+        );
+        return;
+    }
+
+    for change in changes.iter() {
+        info!(event = "config-change", %change);
+    }
+    info!(
+        event = "config-reload",
+        change_count = changes.len(),
+        "reloaded config"
+    );
+
+    // find added devices without waiting for the next poll
+    state.bus_state().check_state_from_device(state).await;
+}
+
+/// Human readable changes between two configs
+pub fn config_diff(old: &Config, new: &Config) -> Vec<String> {
+    let mut changes = Vec::new();
+    map_diff(&mut changes, "bus", &old.buses, &new.buses);
+    map_diff(&mut changes, "device", &old.devices, &new.devices);
+    map_diff(&mut changes, "coil", &old.coils, &new.coils);
+    map_diff(&mut changes, "tag", &old.tags, &new.tags);
+    map_diff(&mut changes, "interlock", &old.interlocks, &new.interlocks);
+    map_diff(&mut changes, "schedule", &old.schedules, &new.schedules);
+    map_diff(&mut changes, "scene", &old.scenes, &new.scenes);
+    if old.retry != new.retry {
+        changes.push("changed retry policy".to_owned());
+    }
+    if old.circuit_breaker != new.circuit_breaker {
+        changes.push("changed circuit breaker".to_owned());
+    }
+    if old.switch_on_delay_ms != new.switch_on_delay_ms {
+        changes.push("changed switch-on delay".to_owned());
+    }
+    changes
+}
+
+fn map_diff<T: PartialEq>(
+    changes: &mut Vec<String>,
+    kind: &str,
+    old: &BTreeMap<String, T>,
+    new: &BTreeMap<String, T>,
+) {
+    for (name, value) in new.iter() {
+        match old.get(name) {
+            None => changes.push(format!("added {} {}", kind, name)),
+            Some(old_value) if old_value != value => {
+                changes.push(format!("changed {} {}", kind, name))
+            }
+            Some(_) => {}
+        }
+    }
+    for name in old.keys().filter(|name| !new.contains_key(*name)) {
+        changes.push(format!("removed {} {}", kind, name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::config_diff;
+    use crate::config::Config;
+
+    #[test]
+    fn diff_of_changed_config() {
+        let old: Config = toml::from_str(include_str!("../example-config.toml")).unwrap();
+        let mut new = old.clone();
+        assert!(config_diff(&old, &new).is_empty());
+
+        let mut coil = new.coils.remove("relay-1").unwrap();
+        coil.address = 7;
+        new.coils.insert("relay-9".to_owned(), coil);
+        new.devices.get_mut("relais-b").unwrap().modbus_address = 3;
+
+        assert_eq!(
+            config_diff(&old, &new),
+            vec![
+                "changed device relais-b",
+                "added coil relay-9",
+                "removed coil relay-1"
+            ]
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use std::sync::atomic;
+
+use tokio::time::{error::Elapsed, sleep};
+use tracing::{info, warn};
+
+use crate::{
+    bus_state::DeviceState,
+    config::RetryableError,
+    state::{StateError, StateResult},
+};
+
+/// Kind of an error in terms of the retry policy
+///
+/// Returns `None` for errors which are never retried.
+pub fn retryable_error(err: &StateError) -> Option<RetryableError> {
+    match err {
+        StateError::Timeout => Some(RetryableError::Timeout),
+        StateError::Io(_) => Some(RetryableError::Transport),
+        StateError::Dorfbus(err) if err.is_exception() => Some(RetryableError::Exception),
+        StateError::Dorfbus(_) => Some(RetryableError::Transport),
+        _ => None,
+    }
+}
+
+/// Flatten the result of a Modbus operation with a timeout
+pub fn modbus_result<T, E>(res: Result<Result<T, E>, Elapsed>) -> StateResult<T>
+where
+    E: Into<StateError>,
+{
+    match res {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(err)) => Err(err.into()),
+        Err(_) => Err(StateError::Timeout),
+    }
+}
+
+/// Attempts of a Modbus operation of a device
+///
+/// Call [`Retry::should_retry`] after a failed attempt and wait with [`Retry::backoff`]
+/// before the next attempt. Pass the final result to [`Retry::finish`].
+pub struct Retry<'a> {
+    device: &'a DeviceState,
+    operation: &'static str,
+    retries: u32,
+}
+
+impl<'a> Retry<'a> {
+    pub fn new(device: &'a DeviceState, operation: &'static str) -> Retry<'a> {
+        Retry {
+            device,
+            operation,
+            retries: 0,
+        }
+    }
+
+    /// Number of retries so far
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Whether the operation should be attempted again after the error
+    ///
+    /// Operations of offline devices are not retried, they are only probed.
+    pub fn should_retry(&self, err: &StateError) -> bool {
+        let policy = &self.device.retry_policy;
+        !self.device.is_offline()
+            && self.retries + 1 < policy.attempts
+            && retryable_error(err).map_or(false, |kind| policy.retry_on.contains(&kind))
+    }
+
+    /// Wait before the next attempt
+    pub async fn backoff(&mut self, err: &StateError) {
+        let delay = self.device.retry_policy.delay(self.retries);
+        self.retries += 1;
+        self.device.retries.fetch_add(1, atomic::Ordering::Relaxed);
+        info!(
+            device = %self.device.name,
+            operation = self.operation,
+            retry = self.retries,
+            delay_ms = delay.as_millis() as u64,
+            %err,
+            "retry modbus operation"
+        );
+        sleep(delay).await;
+    }
+
+    /// Log the final result of a retried operation and update the circuit breaker of the device
+    ///
+    /// Only timeouts and transport errors count as failures,
+    /// a device which answers with an exception is still online.
+    pub fn finish<T>(&self, res: StateResult<T>) -> StateResult<T> {
+        match res.as_ref().map_err(retryable_error) {
+            Ok(_) | Err(Some(RetryableError::Exception)) => self.device.record_success(),
+            Err(Some(RetryableError::Timeout | RetryableError::Transport)) => {
+                self.device.record_failure()
+            }
+            Err(None) => {}
+        }
+
+        if self.retries > 0 {
+            match &res {
+                Ok(_) => info!(
+                    device = %self.device.name,
+                    operation = self.operation,
+                    retries = self.retries,
+                    "modbus operation succeeded after retries"
+                ),
+                Err(err) => warn!(
+                    device = %self.device.name,
+                    operation = self.operation,
+                    retries = self.retries,
+                    %err,
+                    "modbus operation failed after retries"
+                ),
+            }
+        }
+        res
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::{
+    bus_state::{BusState, CoilValue},
+    config::{Config, SceneConfig},
+};
+
+/// Status of a scene
+#[derive(Serialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub struct SceneStatus {
+    pub description: String,
+    /// Value of each coil which is switched by the scene
+    pub coils: BTreeMap<String, bool>,
+    /// The cached states of all coils of the scene match their values
+    pub active: bool,
+    /// Coils whose cached state does not match their value, including coils with an unknown state
+    pub differing: BTreeSet<String>,
+}
+
+impl SceneStatus {
+    /// Compare the values of a scene with the cached states of the coils
+    pub fn new(config: &Config, scene: &SceneConfig, bus_state: &BusState) -> SceneStatus {
+        let coils = config.scene_values(scene);
+        let differing: BTreeSet<String> = coils
+            .iter()
+            .filter(|(name, value)| {
+                let status = bus_state
+                    .coils
+                    .get(name.as_str())
+                    .map(|coil_state| *coil_state.status.read().unwrap());
+                !matches!(
+                    (status, **value),
+                    (Some(CoilValue::On), true) | (Some(CoilValue::Off), false)
+                )
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        SceneStatus {
+            description: scene.description.clone(),
+            coils,
+            active: differing.is_empty(),
+            differing,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::SceneStatus;
+    use crate::{
+        bus_state::{BusState, CoilValue},
+        config::{Config, SceneConfig},
+    };
+
+    #[test]
+    fn scene_is_active_if_all_coils_match() {
+        let config: Config = toml::from_str(include_str!("../example-config.toml")).unwrap();
+        let bus_state = BusState::try_from(&config).unwrap();
+        let scene = SceneConfig {
+            coils: [("relay-1".to_owned(), true), ("relay-2".to_owned(), false)].into(),
+            ..Default::default()
+        };
+
+        *bus_state.coils["relay-1"].status.write().unwrap() = CoilValue::On;
+        let status = SceneStatus::new(&config, &scene, &bus_state);
+        assert!(!status.active);
+        assert_eq!(status.differing, ["relay-2".to_owned()].into());
+
+        *bus_state.coils["relay-2"].status.write().unwrap() = CoilValue::Off;
+        let status = SceneStatus::new(&config, &scene, &bus_state);
+        assert!(status.active);
+        assert!(status.differing.is_empty());
+    }
+}
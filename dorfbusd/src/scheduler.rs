@@ -0,0 +1,188 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Mutex,
+    time::Duration,
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::time::{self, MissedTickBehavior};
+use tracing::{info, instrument, warn};
+
+use crate::{config::ScheduleConfig, state::State};
+
+/// Interval in which the schedules are checked for due runs
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Status of a schedule
+#[derive(Serialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub struct ScheduleStatus {
+    /// Cron expression of the run times
+    pub cron: String,
+    /// Timezone of the cron expression, the local timezone of the host if not given
+    pub timezone: Option<String>,
+    /// The schedule does not run until it is resumed
+    pub paused: bool,
+    /// Next run time as RFC 3339 timestamp, not given if the schedule is paused
+    pub next_run: Option<String>,
+    /// Last run time as RFC 3339 timestamp
+    pub last_run: Option<String>,
+    /// Error of the last run, if it failed
+    pub last_error: Option<String>,
+}
+
+/// The last run of a schedule
+#[derive(Debug, Clone)]
+struct ScheduleRun {
+    time: DateTime<Utc>,
+    error: Option<String>,
+}
+
+/// Runtime state of the schedules
+///
+/// The state is kept by the name of the schedules, so it survives config reloads.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    paused: Mutex<BTreeSet<String>>,
+    runs: Mutex<BTreeMap<String, ScheduleRun>>,
+}
+
+impl Scheduler {
+    pub fn new(paused: BTreeSet<String>) -> Scheduler {
+        Scheduler {
+            paused: Mutex::new(paused),
+            runs: Default::default(),
+        }
+    }
+
+    pub fn is_paused(&self, name: &str) -> bool {
+        self.paused.lock().unwrap().contains(name)
+    }
+
+    /// Pause or resume a schedule, returns whether this changed anything
+    pub fn set_paused(&self, name: &str, paused: bool) -> bool {
+        let mut paused_schedules = self.paused.lock().unwrap();
+        if paused {
+            paused_schedules.insert(name.to_owned())
+        } else {
+            paused_schedules.remove(name)
+        }
+    }
+
+    fn record_run(&self, name: &str, time: DateTime<Utc>, error: Option<String>) {
+        self.runs
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), ScheduleRun { time, error });
+    }
+
+    /// The status of a schedule
+    pub fn status(&self, name: &str, schedule: &ScheduleConfig) -> ScheduleStatus {
+        let paused = self.is_paused(name);
+        let next_run = if paused {
+            None
+        } else {
+            schedule.next_run(&Utc::now()).ok().flatten()
+        };
+        let last_run = self.runs.lock().unwrap().get(name).cloned();
+
+        ScheduleStatus {
+            cron: schedule.cron.clone(),
+            timezone: schedule.timezone.clone(),
+            paused,
+            next_run: next_run.map(|time| time.to_rfc3339()),
+            last_run: last_run
+                .as_ref()
+                .map(|run| run.time.to_rfc3339_opts(SecondsFormat::Secs, false)),
+            last_error: last_run.and_then(|run| run.error),
+        }
+    }
+}
+
+/// Run the configured schedules when they are due
+///
+/// The schedules are taken from the current config on every tick,
+/// so changes of a config reload are picked up.
+/// Runs which were missed, e.g. while the host was suspended, run once.
+#[instrument(skip_all)]
+pub async fn run_schedules(state: State) {
+    let mut interval = time::interval(TICK_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut last_tick = Utc::now();
+
+    loop {
+        interval.tick().await;
+        let now = Utc::now();
+
+        let config = state.config();
+        for (name, schedule) in config.schedules.iter() {
+            if state.scheduler().is_paused(name) {
+                continue;
+            }
+            match schedule.next_run(&last_tick) {
+                Ok(Some(next_run)) if next_run <= now => {
+                    tokio::spawn(run_schedule(state.clone(), name.clone(), schedule.clone()));
+                }
+                Ok(_) => {}
+                Err(err) => warn!(name = %name, %err, "invalid schedule"),
+            }
+        }
+
+        last_tick = now;
+    }
+}
+
+/// Run the action of a schedule and record the result
+async fn run_schedule(state: State, name: String, schedule: ScheduleConfig) {
+    info!(event = "schedule-run", name = %name, "run schedule");
+    // a missing value is rejected by the config validation
+    let value = schedule.value.unwrap_or_default();
+    let result = match (&schedule.coil, &schedule.tag, &schedule.scene) {
+        (Some(coil), _, _) => state
+            .set_coil(coil, value, None, schedule.priority)
+            .await
+            .map(|_| ()),
+        (None, Some(tag), _) => state
+            .set_tag(tag, value, schedule.priority)
+            .await
+            .map(|_| ()),
+        (None, None, Some(scene)) => state
+            .activate_scene(scene, schedule.priority)
+            .await
+            .map(|_| ()),
+        // rejected by the config validation
+        (None, None, None) => Ok(()),
+    };
+
+    if let Err(err) = &result {
+        warn!(event = "schedule-failed", name = %name, %err, "schedule failed");
+    }
+    state
+        .scheduler()
+        .record_run(&name, Utc::now(), result.err().map(|err| err.to_string()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scheduler;
+    use crate::config::ScheduleConfig;
+
+    #[test]
+    fn paused_schedule_has_no_next_run() {
+        let schedule = ScheduleConfig {
+            cron: "0 18 * * *".to_owned(),
+            timezone: Some("UTC".to_owned()),
+            ..Default::default()
+        };
+        let scheduler = Scheduler::default();
+        assert!(scheduler.status("lights", &schedule).next_run.is_some());
+
+        assert!(scheduler.set_paused("lights", true));
+        assert!(!scheduler.set_paused("lights", true));
+        let status = scheduler.status("lights", &schedule);
+        assert!(status.paused);
+        assert_eq!(status.next_run, None);
+    }
+}
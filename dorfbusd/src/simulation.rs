@@ -0,0 +1,355 @@
+use std::{
+    collections::BTreeMap,
+    io::{Error, ErrorKind},
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use dorfbusext::{
+    Exception, ExceptionResponse, DEVICE_ADDRESS_REGISTER, HARDWARE_VERSION_REGISTER,
+};
+use tokio_modbus::{
+    client::Context as ModbusContext,
+    prelude::{Client, Request, Response, Slave, SlaveContext},
+};
+use tracing::debug;
+
+use crate::{
+    config::{Config, DEFAULT_BUS},
+    state::Bus,
+};
+
+/// Hardware version reported by simulated relais cards
+const SIMULATED_HARDWARE_VERSION: u16 = 106;
+
+/// A simulated relais card
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimulatedCard {
+    pub hardware_version: u16,
+    pub coils: Vec<bool>,
+}
+
+impl SimulatedCard {
+    /// A card with `coil_count` coils, which are all off
+    pub fn new(coil_count: usize) -> SimulatedCard {
+        SimulatedCard {
+            hardware_version: SIMULATED_HARDWARE_VERSION,
+            coils: vec![false; coil_count],
+        }
+    }
+}
+
+/// The relais cards on a simulated bus, by their modbus address
+pub type SimulatedCards = Arc<Mutex<BTreeMap<u8, SimulatedCard>>>;
+
+/// An in-process bus which answers like the relais cards
+///
+/// Devices which are not present do not answer, like on a real bus.
+#[derive(Debug)]
+pub struct SimulatedBus {
+    slave: Slave,
+    cards: SimulatedCards,
+}
+
+impl SimulatedBus {
+    pub fn new(cards: SimulatedCards) -> SimulatedBus {
+        SimulatedBus {
+            slave: Slave::broadcast(),
+            cards,
+        }
+    }
+
+    /// Handle a request
+    ///
+    /// Returns `None` if no device answers.
+    fn handle(&self, request: Request) -> Option<Result<Response, Error>> {
+        let mut cards = self.cards.lock().unwrap();
+
+        if self.slave.is_broadcast() {
+            // Broadcasts are executed by all cards and not answered,
+            // except for a change of the device address.
+            // A single relais card answers it from its new address,
+            // which is reported as an invalid response.
+            // Several cards would all get the same address, so this is rejected.
+            if let Request::WriteSingleRegister(DEVICE_ADDRESS_REGISTER, new_addr) = request {
+                return match cards.len() {
+                    0 => None,
+                    1 => {
+                        let card = std::mem::take(&mut *cards).into_values().next()?;
+                        cards.insert(new_addr as u8, card);
+                        Some(Err(invalid_response_header()))
+                    }
+                    _ => Some(Err(Error::new(
+                        ErrorKind::Other,
+                        "several cards answered the change of the device address",
+                    ))),
+                };
+            }
+            return None;
+        }
+
+        let card = cards.get_mut(&self.slave.0)?;
+
+        let response = match request {
+            Request::ReadHoldingRegisters(HARDWARE_VERSION_REGISTER, 1) => {
+                Ok(Response::ReadHoldingRegisters(vec![card.hardware_version]))
+            }
+            Request::ReadHoldingRegisters(_, _) => {
+                Err(exception(0x03, Exception::IllegalDataAddress))
+            }
+            Request::ReadCoils(addr, cnt) => {
+                let range = usize::from(addr)..usize::from(addr) + usize::from(cnt);
+                match card.coils.get(range) {
+                    Some(coils) => {
+                        // coils are transmitted as bytes
+                        let mut coils = coils.to_vec();
+                        coils.resize((coils.len() + 7) / 8 * 8, false);
+                        Ok(Response::ReadCoils(coils))
+                    }
+                    None => Err(exception(0x01, Exception::IllegalDataAddress)),
+                }
+            }
+            Request::WriteSingleCoil(addr, value) => match card.coils.get_mut(usize::from(addr)) {
+                Some(coil) => {
+                    *coil = value;
+                    Ok(Response::WriteSingleCoil(addr, value))
+                }
+                None => Err(exception(0x05, Exception::IllegalDataAddress)),
+            },
+            Request::WriteMultipleCoils(addr, values) => {
+                let range = usize::from(addr)..usize::from(addr) + values.len();
+                match card.coils.get_mut(range) {
+                    Some(coils) => {
+                        coils.copy_from_slice(&values);
+                        Ok(Response::WriteMultipleCoils(addr, values.len() as u16))
+                    }
+                    None => Err(exception(0x0f, Exception::IllegalDataAddress)),
+                }
+            }
+            Request::WriteSingleRegister(DEVICE_ADDRESS_REGISTER, new_addr) => {
+                let card = cards.remove(&self.slave.0)?;
+                cards.insert(new_addr as u8, card);
+                Err(invalid_response_header())
+            }
+            Request::WriteSingleRegister(_, _) => {
+                Err(exception(0x06, Exception::IllegalDataAddress))
+            }
+            Request::Disconnect => Err(Error::from(ErrorKind::NotConnected)),
+            Request::ReadDiscreteInputs(_, _) => Err(exception(0x02, Exception::IllegalFunction)),
+            Request::ReadInputRegisters(_, _) => Err(exception(0x04, Exception::IllegalFunction)),
+            Request::WriteMultipleRegisters(_, _) => {
+                Err(exception(0x10, Exception::IllegalFunction))
+            }
+            Request::ReadWriteMultipleRegisters(_, _, _, _) => {
+                Err(exception(0x17, Exception::IllegalFunction))
+            }
+            Request::Custom(function, _) => Err(exception(function, Exception::IllegalFunction)),
+        };
+
+        Some(response)
+    }
+}
+
+/// The error of a Modbus exception response
+fn exception(function: u8, exception: Exception) -> Error {
+    Error::new(
+        ErrorKind::Other,
+        ExceptionResponse {
+            function,
+            exception,
+        },
+    )
+}
+
+/// The error if a device answers from another address than requested
+fn invalid_response_header() -> Error {
+    Error::new(ErrorKind::InvalidData, "Invalid response header")
+}
+
+impl SlaveContext for SimulatedBus {
+    fn set_slave(&mut self, slave: Slave) {
+        self.slave = slave;
+    }
+}
+
+#[async_trait]
+impl Client for SimulatedBus {
+    async fn call(&mut self, request: Request) -> Result<Response, Error> {
+        debug!(slave = self.slave.0, ?request, "simulated request");
+        match self.handle(request) {
+            Some(response) => response,
+            // a missing device never answers, the caller has to time out
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// The simulated cards of all devices in the config, grouped by bus
+///
+/// Every card has enough coils for the configured coils, rounded up to full bytes.
+pub fn simulated_cards(config: &Config) -> BTreeMap<String, SimulatedCards> {
+    let mut buses: BTreeMap<String, BTreeMap<u8, SimulatedCard>> = BTreeMap::new();
+    if config.buses.is_empty() {
+        buses.insert(DEFAULT_BUS.to_owned(), BTreeMap::new());
+    }
+    for name in config.buses.keys() {
+        buses.insert(name.clone(), BTreeMap::new());
+    }
+
+    for (name, device) in config.devices.iter() {
+        let coil_count = config
+            .coils
+            .values()
+            .filter(|coil| &coil.device == name)
+            .map(|coil| usize::from(coil.address) + 1)
+            .max()
+            .unwrap_or_default();
+        let coil_count = ((coil_count + 7) / 8 * 8).max(8);
+
+        buses
+            .entry(device.bus.clone())
+            .or_default()
+            .insert(device.modbus_address, SimulatedCard::new(coil_count));
+    }
+
+    buses
+        .into_iter()
+        .map(|(name, cards)| (name, Arc::new(Mutex::new(cards))))
+        .collect()
+}
+
+/// Simulated buses for all devices in the config
+pub fn simulated_buses(config: &Config) -> BTreeMap<String, Bus> {
+    simulated_cards(config)
+        .into_iter()
+        .map(|(name, cards)| {
+            let client: Box<dyn Client> = Box::new(SimulatedBus::new(cards));
+            let bus: Bus = Box::new(ModbusContext::from(client));
+            (name, bus)
+        })
+        .collect()
+}
+
+/// A state with simulated buses for all devices in the config, for tests
+#[cfg(test)]
+pub fn simulated_state(config: Config) -> crate::state::State {
+    use std::time::Duration;
+
+    use crate::{cli::Params, persistence::RestorePolicy, state::State};
+
+    let params = Params {
+        port: 8080,
+        transport: None,
+        config_path: "config.toml".to_owned(),
+        config_format: None,
+        poll_interval: Duration::from_secs(10),
+        request_timeout: Duration::from_secs(10),
+        state_path: None,
+        restore_policy: RestorePolicy::Restore,
+        simulate: true,
+        check_config: false,
+    };
+    let buses = simulated_buses(&config);
+    State::new(params, config, buses, None).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use dorfbusext::{DorfbusError, DorfbusExt};
+    use tokio::time::timeout;
+    use tokio_modbus::{
+        client::Context as ModbusContext,
+        prelude::{Client, Reader, Slave, SlaveContext, Writer},
+    };
+
+    use super::{simulated_cards, SimulatedBus, SimulatedCard, SimulatedCards};
+    use crate::config::Config;
+
+    fn simulated_context(cards: &SimulatedCards) -> ModbusContext {
+        let client: Box<dyn Client> = Box::new(SimulatedBus::new(cards.clone()));
+        ModbusContext::from(client)
+    }
+
+    #[test]
+    fn cards_from_example_config() {
+        let config: Config = toml::from_str(include_str!("../example-config.toml")).unwrap();
+        let buses = simulated_cards(&config);
+        let cards = buses.get("default").unwrap().lock().unwrap();
+
+        assert_eq!(cards.len(), 2);
+        assert_eq!(cards.get(&1).unwrap().coils.len(), 8);
+    }
+
+    #[tokio::test]
+    async fn read_and_write_coils() {
+        let cards = SimulatedCards::default();
+        cards.lock().unwrap().insert(1, SimulatedCard::new(8));
+        let mut modbus = simulated_context(&cards);
+
+        modbus.set_slave(Slave(1));
+        assert_eq!(modbus.read_hardware_version().await.unwrap(), 106);
+
+        modbus.write_single_coil(2, true).await.unwrap();
+        assert_eq!(
+            modbus.read_coil_states(1, 3).await.unwrap(),
+            vec![false, true, false]
+        );
+        assert!(modbus.read_coil_states(6, 4).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unsupported_requests_are_exceptions() {
+        let cards = SimulatedCards::default();
+        cards.lock().unwrap().insert(1, SimulatedCard::new(8));
+        let mut modbus = simulated_context(&cards);
+
+        modbus.set_slave(Slave(1));
+        let err = modbus.read_coil_states(6, 4).await.unwrap_err();
+        assert!(err.is_exception());
+        assert!(!err.is_illegal_function());
+
+        let err = DorfbusError::from(modbus.read_input_registers(0, 1).await.unwrap_err());
+        assert!(err.is_illegal_function());
+    }
+
+    #[tokio::test]
+    async fn missing_device_does_not_answer() {
+        let cards = SimulatedCards::default();
+        let mut modbus = simulated_context(&cards);
+
+        modbus.set_slave(Slave(1));
+        let res = timeout(Duration::from_millis(10), modbus.read_hardware_version()).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn set_device_address() {
+        let cards = SimulatedCards::default();
+        cards.lock().unwrap().insert(1, SimulatedCard::new(8));
+        let mut modbus = simulated_context(&cards);
+
+        modbus.set_slave(Slave::broadcast());
+        modbus.set_device_address(5).await.unwrap();
+
+        let addresses: Vec<_> = cards.lock().unwrap().keys().copied().collect();
+        assert_eq!(addresses, vec![5]);
+        modbus.set_slave(Slave(5));
+        assert_eq!(modbus.read_hardware_version().await.unwrap(), 106);
+    }
+
+    #[tokio::test]
+    async fn set_device_address_of_several_cards() {
+        let cards = SimulatedCards::default();
+        cards.lock().unwrap().insert(1, SimulatedCard::new(8));
+        cards.lock().unwrap().insert(2, SimulatedCard::new(8));
+        let mut modbus = simulated_context(&cards);
+
+        modbus.set_slave(Slave::broadcast());
+        assert!(modbus.set_device_address(5).await.is_err());
+
+        let addresses: Vec<_> = cards.lock().unwrap().keys().copied().collect();
+        assert_eq!(addresses, vec![1, 2]);
+    }
+}
@@ -1,76 +1,675 @@
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future::Future,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, ensure};
 
-use tokio::sync::{oneshot, Mutex as TokioMutex};
-use tokio_modbus::client::Context as ModbusContext;
-use tracing::{info, instrument};
+use dorfbusext::DorfbusBus;
+use tokio::{
+    sync::{oneshot, Mutex as TokioMutex},
+    time::sleep,
+};
+use tracing::{info, instrument, warn};
 
 use crate::{
-    bus_state::{BusState, CoilState, CoilUpdate},
+    bus_queue::{BusQueue, BusStatus, Priority},
+    bus_state::{BusState, CoilState, CoilUpdate, CoilValue, DeviceState},
     cli::Params,
-    config::Config,
+    config::{CoilMode, Config, InterlockConfig, InterlockPolicy, DEFAULT_BUS},
+    interlock::Interlock,
+    jobs::{JobStatus, Jobs},
+    persistence::{RestorePolicy, StateStore},
+    scene::SceneStatus,
+    scheduler::{ScheduleStatus, Scheduler},
 };
 
+/// A bus with relais cards, independent of the transport
+pub type Bus = Box<dyn DorfbusBus>;
+
+/// Delay before switching off a pulsed coil is tried again, doubled after every try
+const PULSE_OFF_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Number of times switching off a pulsed coil is tried again
+const PULSE_OFF_RETRIES: u32 = 6;
+
 #[derive(Clone)]
 pub struct State {
     inner: Arc<StateInner>,
 }
 
 impl State {
-    pub fn new(params: Params, config: Config, modbus: ModbusContext) -> anyhow::Result<State> {
+    /// Create the state and spawn a worker for each bus
+    ///
+    /// This has to be called within a tokio runtime.
+    pub fn new(
+        params: Params,
+        config: Config,
+        buses: BTreeMap<String, Bus>,
+        state_store: Option<StateStore>,
+    ) -> anyhow::Result<State> {
         let bus_state = Arc::new(BusState::try_from(&config)?);
+        if let Some(store) = &state_store {
+            for (name, coil) in bus_state.coils.iter() {
+                *coil.auto_off.write().unwrap() = store.auto_off(name);
+            }
+        }
+        let buses: BTreeMap<_, _> = buses
+            .into_iter()
+            .map(|(name, modbus)| (name, Arc::new(TokioMutex::new(modbus))))
+            .collect();
+        let paused_schedules = state_store
+            .as_ref()
+            .map(StateStore::paused_schedules)
+            .unwrap_or_default();
+        let bus_queues = buses
+            .keys()
+            .map(|name| (name.clone(), Arc::new(BusQueue::default())))
+            .collect();
 
-        Ok(State {
+        let state = State {
             inner: Arc::new(StateInner {
                 params,
-                config,
-                modbus: Arc::new(TokioMutex::new(modbus)),
-                bus_state,
+                config: RwLock::new(Arc::new(config)),
+                buses,
+                bus_queues,
+                bus_state: RwLock::new(bus_state),
+                state_store,
+                interlocks: Default::default(),
+                scheduler: Scheduler::new(paused_schedules),
+                jobs: Jobs::default(),
             }),
-        })
+        };
+
+        for (name, queue) in state.inner.bus_queues.iter() {
+            tokio::spawn(queue.clone().run(name.clone(), state.clone()));
+        }
+
+        Ok(state)
     }
 
     pub fn params(&self) -> &Params {
         &self.inner.params
     }
 
-    pub fn config(&self) -> &Config {
-        &self.inner.config
+    /// The current config
+    ///
+    /// The config can be replaced by [`State::reload_config`] at any time.
+    pub fn config(&self) -> Arc<Config> {
+        self.inner.config.read().unwrap().clone()
+    }
+
+    /// Replace the config and rebuild the bus state
+    ///
+    /// The state of devices and coils whose address did not change is kept.
+    /// An invalid config is rejected and the old config stays in use.
+    /// Pending writes still update the coils of the old bus state,
+    /// their status is read again with the next poll.
+    pub fn reload_config(&self, config: Config) -> anyhow::Result<()> {
+        let old_config = self.config();
+
+        // the buses are only connected on startup
+        let bus_names: BTreeSet<&str> = if config.buses.is_empty() {
+            [DEFAULT_BUS].into()
+        } else {
+            config.buses.keys().map(String::as_str).collect()
+        };
+        let connected_buses: BTreeSet<&str> = self.inner.buses.keys().map(String::as_str).collect();
+        ensure!(
+            bus_names == connected_buses,
+            "adding or removing buses requires a restart"
+        );
+        for (name, bus) in config.buses.iter() {
+            if let Some(old_bus) = old_config.buses.get(name) {
+                if (&bus.url, bus.boud) != (&old_bus.url, old_bus.boud) {
+                    bail!("changing the connection of bus {} requires a restart", name);
+                }
+            }
+        }
+
+        let bus_state = BusState::try_from(&config)?;
+        bus_state.keep_state_from(&self.bus_state());
+
+        *self.inner.config.write().unwrap() = Arc::new(config);
+        *self.inner.bus_state.write().unwrap() = Arc::new(bus_state);
+        Ok(())
+    }
+
+    /// The modbus context of a bus
+    ///
+    /// Every bus has its own lock, so devices on different buses can be used concurrently.
+    /// The lock is only taken by the worker of the bus, use [`State::bus_queue`] to send commands.
+    pub fn bus(&self, name: &str) -> StateResult<&Arc<TokioMutex<Bus>>> {
+        self.inner
+            .buses
+            .get(name)
+            .ok_or_else(|| StateError::BusNotFound(name.to_string()))
+    }
+
+    /// The command queue of a bus
+    pub fn bus_queue(&self, name: &str) -> StateResult<&Arc<BusQueue>> {
+        self.inner
+            .bus_queues
+            .get(name)
+            .ok_or_else(|| StateError::BusNotFound(name.to_string()))
+    }
+
+    /// The command queue of the bus a device is connected to
+    pub fn device_queue(&self, device: &DeviceState) -> StateResult<&Arc<BusQueue>> {
+        self.bus_queue(&device.config.bus)
     }
 
-    pub fn modbus(&self) -> &Arc<TokioMutex<ModbusContext>> {
-        &self.inner.modbus
+    /// The status of all buses
+    pub fn bus_statuses(&self) -> BTreeMap<String, BusStatus> {
+        self.inner
+            .bus_queues
+            .iter()
+            .map(|(name, queue)| (name.clone(), queue.status()))
+            .collect()
     }
 
     pub fn bus_state(&self) -> Arc<BusState> {
-        self.inner.bus_state.clone()
+        self.inner.bus_state.read().unwrap().clone()
+    }
+
+    pub fn state_store(&self) -> Option<&StateStore> {
+        self.inner.state_store.as_ref()
+    }
+
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.inner.scheduler
+    }
+
+    /// Run a switching operation in the background
+    ///
+    /// The returned status can be polled with [`State::job_status`].
+    pub fn spawn_job(
+        &self,
+        job: impl Future<Output = StateResult<Vec<CoilUpdate>>> + Send + 'static,
+    ) -> JobStatus {
+        let status = self.inner.jobs.start();
+        let id = status.id;
+        let state = self.clone();
+        tokio::spawn(async move {
+            let result = job.await;
+            if let Err(err) = &result {
+                warn!(event = "job-failed", id, %err, "job failed");
+            }
+            state.inner.jobs.finish(id, result);
+        });
+        status
+    }
+
+    /// The status of a job
+    pub fn job_status(&self, id: u64) -> StateResult<JobStatus> {
+        self.inner
+            .jobs
+            .status(id)
+            .ok_or(StateError::JobNotFound(id))
+    }
+
+    /// The status of all configured schedules
+    pub fn schedule_statuses(&self) -> BTreeMap<String, ScheduleStatus> {
+        self.config()
+            .schedules
+            .iter()
+            .map(|(name, schedule)| (name.clone(), self.scheduler().status(name, schedule)))
+            .collect()
+    }
+
+    /// Pause or resume a schedule
+    ///
+    /// Paused schedules are recorded in the state file, so they stay paused after a restart.
+    #[instrument(skip(self))]
+    pub async fn pause_schedule(&self, name: &str, paused: bool) -> StateResult<ScheduleStatus> {
+        let config = self.config();
+        let schedule = config
+            .schedules
+            .get(name)
+            .ok_or_else(|| StateError::ScheduleNotFound(name.to_string()))?;
+
+        if self.scheduler().set_paused(name, paused) {
+            info!(event = "schedule-paused", paused, "paused schedule");
+        }
+        if let Some(store) = self.state_store() {
+            store.set_schedule_paused(name, paused).await;
+        }
+        Ok(self.scheduler().status(name, schedule))
+    }
+
+    /// Value which should be written to a coil if its device (re)appears on the bus
+    ///
+    /// This is the persisted value if the restore policy is `restore`,
+    /// otherwise `None` and the configured default status should be used.
+    pub fn restore_value(&self, name: &str) -> Option<bool> {
+        self.state_store()
+            .filter(|store| store.policy() == RestorePolicy::Restore)
+            .and_then(|store| store.coil_value(name))
+    }
+
+    /// Reset the bus state and read the current state back from the devices
+    ///
+    /// The coil states are seeded with the persisted values until they are read from the devices.
+    /// Call this after a powerloss on modbus.
+    pub async fn reset_bus_state(&self) {
+        let bus_state = self.bus_state();
+        bus_state.reset();
+        if let Some(store) = self.state_store() {
+            for (name, coil) in bus_state.coils.iter() {
+                if let Some(value) = store.coil_value(name) {
+                    *coil.status.write().unwrap() = value.into();
+                }
+            }
+        }
+        bus_state.check_state_from_device(self).await;
+    }
+
+    /// Record the desired value of a coil in the state file
+    async fn persist_coil(&self, coil_state: &CoilState, value: bool) {
+        self.persist_coils(&[(coil_state, value)]).await;
+    }
+
+    /// Record the desired values of several coils in the state file, with a single write
+    ///
+    /// Momentary coils are only on for a pulse, so they are always persisted as off.
+    /// Switching a coil on starts its auto-off timer if it has a maximum on time.
+    /// Switching it off keeps the timer until the coil was written,
+    /// so a coil whose write fails is still switched off by its timer.
+    async fn persist_coils(&self, coils: &[(&CoilState, bool)]) {
+        let mut values = Vec::new();
+        for (coil_state, value) in coils {
+            let value = *value && coil_state.config.mode != CoilMode::Momentary;
+            let mut auto_off = coil_state.auto_off.write().unwrap();
+            if value {
+                *auto_off = coil_state
+                    .config
+                    .max_on_time()
+                    .map(|max_on_time| SystemTime::now() + max_on_time);
+            }
+            values.push((coil_state.name.as_str(), value, *auto_off));
+        }
+
+        if let Some(store) = self.state_store() {
+            store.set_coil_values(&values).await;
+        }
+    }
+
+    /// Start or cancel the auto-off timer of a coil
+    async fn set_auto_off(&self, coil_state: &CoilState, off_at: Option<SystemTime>) {
+        *coil_state.auto_off.write().unwrap() = off_at;
+        if let Some(store) = self.state_store() {
+            store.set_auto_off(&coil_state.name, off_at).await;
+        }
+    }
+
+    /// Record the value a coil will have after a switch in the state file
+    async fn persist_switch(&self, coil_state: &CoilState, switch: Switch) {
+        let value = match switch {
+            Switch::Set(value) => value,
+            Switch::Pulse(_) => false,
+            // the new value of a toggled coil is persisted once it is known
+            Switch::Toggle if coil_state.config.mode != CoilMode::Momentary => return,
+            Switch::Toggle => false,
+            Switch::Restore(_) => return,
+        };
+        self.persist_coil(coil_state, value).await;
+    }
+
+    /// Persist and queue switching a coil
+    ///
+    /// Coils of an interlock group are switched by their own task, see [`State::switch_interlocked`].
+    async fn switch_coil(
+        &self,
+        coil_state: &CoilState,
+        switch: Switch,
+        priority: Priority,
+    ) -> StateResult<oneshot::Receiver<StateResult<CoilUpdate>>> {
+        let config = self.config();
+        if let Some((group, interlock)) = config.interlock_of(&coil_state.name) {
+            let (tx, rx) = oneshot::channel();
+            let state = self.clone();
+            let coil = coil_state.clone();
+            let group = group.to_owned();
+            let interlock = interlock.clone();
+
+            // the group stays locked until the write is finished, even if the caller is cancelled
+            tokio::spawn(async move {
+                let result = state
+                    .switch_interlocked(&group, &interlock, &coil, switch, priority)
+                    .await;
+                let _tx_result = tx.send(result);
+            });
+            return Ok(rx);
+        }
+
+        self.persist_switch(coil_state, switch).await;
+        self.switch_unlocked(coil_state, switch, priority)
+    }
+
+    /// Queue switching a coil, without looking at its interlock group
+    ///
+    /// Switching a momentary coil on sends a pulse of its configured duration.
+    fn switch_unlocked(
+        &self,
+        coil_state: &CoilState,
+        switch: Switch,
+        priority: Priority,
+    ) -> StateResult<oneshot::Receiver<StateResult<CoilUpdate>>> {
+        let momentary = coil_state.config.mode == CoilMode::Momentary;
+        match switch {
+            Switch::Set(true) | Switch::Toggle if momentary => {
+                self.spawn_pulse(coil_state, None, priority)
+            }
+            Switch::Set(enabled) | Switch::Restore(enabled) => {
+                let queue = self.device_queue(&coil_state.device)?;
+                Ok(queue.write_coil(coil_state, enabled, priority))
+            }
+            Switch::Pulse(duration) => self.spawn_pulse(coil_state, duration, priority),
+            Switch::Toggle => self.spawn_toggle(coil_state, priority),
+        }
+    }
+
+    /// Switch a coil of an interlock group
+    ///
+    /// The group is locked while the coil is switched.
+    /// Before a coil is switched on, the other coils of the group are checked, see [`State::resolve_interlock`].
+    async fn switch_interlocked(
+        &self,
+        group: &str,
+        config: &InterlockConfig,
+        coil_state: &CoilState,
+        switch: Switch,
+        priority: Priority,
+    ) -> StateResult<CoilUpdate> {
+        let interlock = self.interlock(group);
+        let _lock = interlock.lock.lock().await;
+
+        let switch = match switch {
+            Switch::Toggle if coil_state.config.mode != CoilMode::Momentary => {
+                let mut status = *coil_state.status.read().unwrap();
+                if let CoilValue::Unknown = status {
+                    let queue = self.device_queue(&coil_state.device)?;
+                    status = coil_state.refresh_coil(queue, priority).await?.status;
+                }
+                Switch::Set(!matches!(status, CoilValue::On))
+            }
+            Switch::Toggle => Switch::Pulse(None),
+            switch => switch,
+        };
+        let switches_on = matches!(
+            switch,
+            Switch::Set(true) | Switch::Pulse(_) | Switch::Restore(true)
+        );
+        let was_off = matches!(*coil_state.status.read().unwrap(), CoilValue::Off);
+
+        if switches_on {
+            self.resolve_interlock(group, config, &interlock, coil_state, priority)
+                .await?;
+        }
+        self.persist_switch(coil_state, switch).await;
+        let result = self.switch_unlocked(coil_state, switch, priority)?.await?;
+
+        let is_off = matches!(*coil_state.status.read().unwrap(), CoilValue::Off);
+        if is_off && (switches_on || !was_off) {
+            interlock.record_off(&coil_state.name);
+        }
+        result
     }
 
+    /// Make sure that no other coil of the interlock group is on, before a coil is switched on
+    ///
+    /// Coils with an unknown state count as on.
+    /// Depending on the policy of the group, the request is rejected or the other coils are switched off.
+    /// Afterwards the dead time of the group is waited for.
+    async fn resolve_interlock(
+        &self,
+        group: &str,
+        config: &InterlockConfig,
+        interlock: &Interlock,
+        coil_state: &CoilState,
+        priority: Priority,
+    ) -> StateResult<()> {
+        let bus_state = self.bus_state();
+        let others = config
+            .coils
+            .iter()
+            .filter(|name| **name != coil_state.name)
+            .filter_map(|name| bus_state.coils.get(name))
+            .filter(|other| !matches!(*other.status.read().unwrap(), CoilValue::Off));
+
+        for other in others {
+            match config.policy {
+                InterlockPolicy::Reject => {
+                    return Err(StateError::Interlocked {
+                        coil: coil_state.name.clone(),
+                        other: other.name.clone(),
+                        group: group.to_owned(),
+                    })
+                }
+                InterlockPolicy::SwitchOff => {
+                    info!(name = %other.name, interlock = %group, "switch off interlocked coil");
+                    self.persist_coil(other, false).await;
+                    self.switch_unlocked(other, Switch::Set(false), priority)?
+                        .await??;
+                    interlock.record_off(&other.name);
+                }
+            }
+        }
+
+        let dead_time = Duration::from_millis(config.dead_time_ms);
+        let remaining = interlock.remaining_dead_time(&coil_state.name, dead_time);
+        if !remaining.is_zero() {
+            info!(name = %coil_state.name, interlock = %group, ?remaining, "wait for dead time");
+            sleep(remaining).await;
+        }
+        Ok(())
+    }
+
+    /// The runtime state of an interlock group
+    fn interlock(&self, group: &str) -> Arc<Interlock> {
+        self.inner
+            .interlocks
+            .lock()
+            .unwrap()
+            .entry(group.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    /// Spawn a task which switches a coil on for `duration` and off again
+    ///
+    /// The pulse runs in its own task, so the coil is switched off
+    /// even if the caller is cancelled, e.g. because the HTTP client disconnected.
+    /// The coil is also switched off if switching it on failed,
+    /// unless the write was never sent because the device is offline.
+    /// Switching the coil off is tried again a few times with an increasing delay,
+    /// the caller receives the result of the first try.
+    /// A device which is offline gets its coils written again when it reappears.
+    fn spawn_pulse(
+        &self,
+        coil_state: &CoilState,
+        duration: Option<Duration>,
+        priority: Priority,
+    ) -> StateResult<oneshot::Receiver<StateResult<CoilUpdate>>> {
+        let queue = self.device_queue(&coil_state.device)?.clone();
+        let coil = coil_state.clone();
+        let duration = duration.unwrap_or_else(|| Duration::from_millis(coil.config.pulse_ms));
+        let (tx, rx) = oneshot::channel();
+
+        tokio::spawn(async move {
+            info!(name = %coil.name, ?duration, "pulse coil");
+            let on_result = queue
+                .write_coil(&coil, true, priority)
+                .await
+                .map_err(StateError::from)
+                .and_then(|res| res);
+            match on_result {
+                Ok(_) => sleep(duration).await,
+                Err(StateError::DeviceOffline(_)) => {
+                    let _tx_result = tx.send(on_result);
+                    return;
+                }
+                Err(_) => {}
+            }
+
+            let mut off_result = switch_off_pulsed(&queue, &coil).await;
+            let _tx_result = tx.send(on_result.and(off_result.clone()));
+
+            let mut delay = PULSE_OFF_RETRY_DELAY;
+            for _ in 0..PULSE_OFF_RETRIES {
+                if let Ok(_) | Err(StateError::DeviceOffline(_)) = off_result {
+                    break;
+                }
+                sleep(delay).await;
+                delay *= 2;
+                off_result = switch_off_pulsed(&queue, &coil).await;
+                if off_result.is_ok() {
+                    info!(name = %coil.name, "switched off pulsed coil");
+                }
+            }
+            if off_result.is_err() {
+                warn!(name = %coil.name, "gave up switching off pulsed coil");
+            }
+        });
+
+        Ok(rx)
+    }
+
+    /// Get the state of a coil
+    ///
+    /// If `refresh` is set, the state is read from the device first.
     #[instrument(skip(self))]
-    pub async fn get_coil(&self, name: &str) -> StateResult<CoilUpdate> {
+    pub async fn get_coil(&self, name: &str, refresh: bool) -> StateResult<CoilUpdate> {
         let bus_state = self.bus_state();
         let coil_state = bus_state
             .coils
             .get(name)
             .ok_or_else(|| StateError::CoilNotFound(name.to_string()))?;
-        let coil_update = coil_state.get_coil().await?;
+        let coil_update = if refresh {
+            let queue = self.device_queue(&coil_state.device)?;
+            coil_state.refresh_coil(queue, Priority::Normal).await?
+        } else {
+            coil_state.get_coil().await?
+        };
         Ok(coil_update)
     }
 
+    /// Set the state of a coil
+    ///
+    /// The write is queued on the bus of the coil with the given priority.
+    /// Switching on a coil of an interlock group follows the policy of the group.
+    /// If the coil is switched on for a `duration`, it is switched off automatically afterwards.
+    /// The duration is limited to the maximum on time of the coil.
     #[instrument(skip(self))]
-    pub async fn set_coil(&self, name: &str, enabled: bool) -> StateResult<CoilUpdate> {
-        info!("locking modbus device...");
-        let modbus = self.modbus();
+    pub async fn set_coil(
+        &self,
+        name: &str,
+        enabled: bool,
+        duration: Option<Duration>,
+        priority: Priority,
+    ) -> StateResult<CoilUpdate> {
+        let bus_state = self.bus_state();
+        let coil_state = bus_state
+            .coils
+            .get(name)
+            .ok_or_else(|| StateError::CoilNotFound(name.to_string()))?;
 
+        let coil_update = self
+            .switch_coil(coil_state, Switch::Set(enabled), priority)
+            .await?
+            .await??;
+
+        // replaces the timer of the maximum on time, which was started by the switch
+        let momentary = coil_state.config.mode == CoilMode::Momentary;
+        if let (true, false, Some(duration)) = (enabled, momentary, duration) {
+            let duration = match coil_state.config.max_on_time() {
+                Some(max_on_time) => duration.min(max_on_time),
+                None => duration,
+            };
+            self.set_auto_off(coil_state, Some(SystemTime::now() + duration))
+                .await;
+        }
+
+        Ok(coil_update)
+    }
+
+    /// Switch a coil on for `duration` and off again
+    ///
+    /// Without a duration the configured pulse duration of the coil is used.
+    #[instrument(skip(self))]
+    pub async fn pulse_coil(
+        &self,
+        name: &str,
+        duration: Option<Duration>,
+        priority: Priority,
+    ) -> StateResult<CoilUpdate> {
         let bus_state = self.bus_state();
         let coil_state = bus_state
             .coils
             .get(name)
             .ok_or_else(|| StateError::CoilNotFound(name.to_string()))?;
-        let coil_update = coil_state.set_coil(modbus.clone(), enabled).await?;
+
+        let coil_update = self
+            .switch_coil(coil_state, Switch::Pulse(duration), priority)
+            .await?
+            .await??;
 
         Ok(coil_update)
     }
 
+    /// Switch a coil to the opposite of its current state
+    ///
+    /// The coil is read and written by a single command on its bus,
+    /// so a concurrent write of another client can not get in between.
+    /// Writes which are still queued for the coil are sent afterwards.
+    /// Coils of an interlock group are read and written while holding the lock of the group instead.
+    /// Momentary coils are off at rest, so toggling them sends a pulse.
+    #[instrument(skip(self))]
+    pub async fn toggle_coil(&self, name: &str) -> StateResult<CoilUpdate> {
+        let bus_state = self.bus_state();
+        let coil_state = bus_state
+            .coils
+            .get(name)
+            .ok_or_else(|| StateError::CoilNotFound(name.to_string()))?;
+
+        self.switch_coil(coil_state, Switch::Toggle, Priority::Normal)
+            .await?
+            .await?
+    }
+
+    /// Queue toggling a coil as a single command on its bus
+    ///
+    /// The new value is persisted even if the caller is cancelled.
+    fn spawn_toggle(
+        &self,
+        coil_state: &CoilState,
+        priority: Priority,
+    ) -> StateResult<oneshot::Receiver<StateResult<CoilUpdate>>> {
+        let (tx, rx) = oneshot::channel();
+        let coil = coil_state.clone();
+        let toggled = self
+            .device_queue(&coil.device)?
+            .exclusive(priority, move |bus| {
+                Box::pin(async move { coil.toggle_coil(bus).await })
+            });
+        let coil = coil_state.clone();
+        let state = self.clone();
+
+        tokio::spawn(async move {
+            let result = toggled.await.map_err(StateError::from).and_then(|res| res);
+            if let Ok((value, _)) = result {
+                info!(name = %coil.name, value, "toggled coil");
+                state.persist_coil(&coil, value).await;
+            }
+            let _tx_result =
+                tx.send(result.map(|(_, retries)| coil.as_update().with_retries(retries)));
+        });
+
+        Ok(rx)
+    }
+
     #[instrument(skip(self))]
     pub async fn get_tag(&self, name: &str) -> StateResult<Vec<CoilUpdate>> {
         let bus_state = self.bus_state();
@@ -86,47 +685,420 @@ impl State {
         Ok(coil_updates)
     }
 
+    /// Set the state of all coils with a tag
+    ///
+    /// The writes are queued on the buses of the coils with the given priority.
+    /// Momentary coils are pulsed instead of switched on.
+    /// If the tag contains several coils of an interlock group, at most one of them ends up on.
+    /// With a switch-on delay, the coils are switched on one after another in the order of the tag,
+    /// switching off is not delayed.
     #[instrument(skip(self))]
-    pub async fn set_tag(&self, name: &str, enabled: bool) -> StateResult<Vec<CoilUpdate>> {
-        info!("locking modbus device...");
-        let modbus = self.modbus();
-
+    pub async fn set_tag(
+        &self,
+        name: &str,
+        enabled: bool,
+        priority: Priority,
+    ) -> StateResult<Vec<CoilUpdate>> {
         let bus_state = self.bus_state();
-
         let coils = bus_state
             .tags
             .get(name)
             .ok_or_else(|| StateError::TagNotFound(name.to_string()))?;
 
+        let config = self.config();
+        let delay = config.switch_on_delay(name);
+        if enabled && !delay.is_zero() {
+            let mut coils = coils.clone();
+            if let Some(tag) = config.tags.get(name) {
+                coils.sort_by_key(|coil_state| tag.switch_on_position(&coil_state.name));
+            }
+
+            // the remaining coils are switched on, even if the caller is cancelled
+            let (tx, rx) = oneshot::channel();
+            let state = self.clone();
+            tokio::spawn(async move {
+                let result = state.switch_on_staggered(&coils, delay, priority).await;
+                let _tx_result = tx.send(result);
+            });
+            return rx.await?;
+        }
+
+        let coils: Vec<_> = coils
+            .iter()
+            .map(|coil_state| (coil_state.as_ref(), enabled))
+            .collect();
+        self.set_coils(&coils, priority).await
+    }
+
+    /// Switch coils on one after another
+    ///
+    /// The delay is only awaited after coils which were switched on, so no inrush current is expected.
+    /// A coil which can not be switched on does not stop the other coils.
+    async fn switch_on_staggered(
+        &self,
+        coils: &[Arc<CoilState>],
+        delay: Duration,
+        priority: Priority,
+    ) -> StateResult<Vec<CoilUpdate>> {
         let mut results = Vec::new();
+        let mut switched_on = false;
         for coil_state in coils {
-            results.push(coil_state.set_coil(modbus.clone(), enabled).await);
+            let was_on = matches!(*coil_state.status.read().unwrap(), CoilValue::On);
+            if switched_on && !was_on {
+                sleep(delay).await;
+            }
+            let result = match self
+                .switch_coil(coil_state, Switch::Set(true), priority)
+                .await
+            {
+                Ok(rx) => rx.await.map_err(StateError::from).and_then(|res| res),
+                Err(err) => Err(err),
+            };
+            switched_on |= !was_on && result.is_ok();
+            results.push(result);
         }
 
-        let final_result: StateResult<Vec<_>> = results.into_iter().collect();
+        results
+            .into_iter()
+            .zip(coils)
+            .map(|(result, coil_state)| {
+                result.map(|update| coil_state.as_update().with_retries(update.retries))
+            })
+            .collect()
+    }
+
+    /// Set the states of several coils
+    ///
+    /// Returns the current state of each coil once all writes are finished.
+    async fn set_coils(
+        &self,
+        coils: &[(&CoilState, bool)],
+        priority: Priority,
+    ) -> StateResult<Vec<CoilUpdate>> {
+        // coils of an interlock group are persisted by their own task
+        let config = self.config();
+        let unlocked: Vec<_> = coils
+            .iter()
+            .filter(|(coil_state, _)| config.interlock_of(&coil_state.name).is_none())
+            .copied()
+            .collect();
+        self.persist_coils(&unlocked).await;
+
+        // queue all writes first, so the writes of a device are grouped
+        // and writes on different buses run concurrently
+        let mut pending = Vec::new();
+        for (coil_state, enabled) in coils {
+            let switch = Switch::Set(*enabled);
+            pending.push(if config.interlock_of(&coil_state.name).is_some() {
+                self.switch_coil(coil_state, switch, priority).await
+            } else {
+                self.switch_unlocked(coil_state, switch, priority)
+            });
+        }
+
+        let mut results = Vec::new();
+        for rx in pending {
+            let result = match rx {
+                Ok(rx) => rx.await.map_err(StateError::from).and_then(|res| res),
+                Err(err) => Err(err),
+            };
+            results.push(result);
+        }
+
+        // an interlock group may have switched another of the coils off in the meantime
+        let final_result: StateResult<Vec<_>> = results
+            .into_iter()
+            .zip(coils)
+            .map(|(result, (coil_state, _))| {
+                result.map(|update| coil_state.as_update().with_retries(update.retries))
+            })
+            .collect();
         final_result
     }
+
+    /// Restore the states of coils after their device reappeared, e.g. to their default status
+    ///
+    /// The interlock groups of the coils are checked like for a client, but the values are not persisted.
+    /// Momentary coils are written instead of pulsed.
+    /// Returns the result of each coil in the given order.
+    pub async fn restore_coils(
+        &self,
+        coils: &[(&CoilState, bool)],
+        priority: Priority,
+    ) -> Vec<StateResult<CoilUpdate>> {
+        let mut pending = Vec::new();
+        for (coil_state, value) in coils {
+            pending.push(
+                self.switch_coil(coil_state, Switch::Restore(*value), priority)
+                    .await,
+            );
+        }
+
+        let mut results = Vec::new();
+        for rx in pending {
+            results.push(match rx {
+                Ok(rx) => rx.await.map_err(StateError::from).and_then(|res| res),
+                Err(err) => Err(err),
+            });
+        }
+        results
+    }
+
+    /// Toggle all coils with a tag
+    ///
+    /// If any coil of the tag is on, all coils are switched off, otherwise all coils are switched on.
+    /// The decision is based on the cached states, coils with an unknown state count as off.
+    #[instrument(skip(self))]
+    pub async fn toggle_tag(&self, name: &str, priority: Priority) -> StateResult<Vec<CoilUpdate>> {
+        let any_on = self
+            .bus_state()
+            .tags
+            .get(name)
+            .ok_or_else(|| StateError::TagNotFound(name.to_string()))?
+            .iter()
+            .any(|coil_state| matches!(*coil_state.status.read().unwrap(), CoilValue::On));
+
+        self.set_tag(name, !any_on, priority).await
+    }
+
+    /// The status of all configured scenes
+    pub fn scene_statuses(&self) -> BTreeMap<String, SceneStatus> {
+        let config = self.config();
+        let bus_state = self.bus_state();
+        config
+            .scenes
+            .iter()
+            .map(|(name, scene)| {
+                let status = SceneStatus::new(&config, scene, &bus_state);
+                (name.clone(), status)
+            })
+            .collect()
+    }
+
+    /// The status of a scene
+    pub fn scene_status(&self, name: &str) -> StateResult<SceneStatus> {
+        let config = self.config();
+        let scene = config
+            .scenes
+            .get(name)
+            .ok_or_else(|| StateError::SceneNotFound(name.to_string()))?;
+        Ok(SceneStatus::new(&config, scene, &self.bus_state()))
+    }
+
+    /// Switch the coils of a scene to their values
+    ///
+    /// The coils are switched off before the other coils are switched on,
+    /// so a scene can change which coil of an interlock group is on.
+    #[instrument(skip(self))]
+    pub async fn activate_scene(
+        &self,
+        name: &str,
+        priority: Priority,
+    ) -> StateResult<Vec<CoilUpdate>> {
+        let config = self.config();
+        let scene = config
+            .scenes
+            .get(name)
+            .ok_or_else(|| StateError::SceneNotFound(name.to_string()))?;
+        let bus_state = self.bus_state();
+
+        let mut off = Vec::new();
+        let mut on = Vec::new();
+        for (coil, value) in config.scene_values(scene) {
+            let coil_state = bus_state
+                .coils
+                .get(&coil)
+                .ok_or(StateError::CoilNotFound(coil))?;
+            if value {
+                on.push((coil_state.as_ref(), true));
+            } else {
+                off.push((coil_state.as_ref(), false));
+            }
+        }
+
+        info!(event = "scene-activated", "activate scene");
+        let mut coil_updates = self.set_coils(&off, priority).await?;
+        coil_updates.extend(self.set_coils(&on, priority).await?);
+        coil_updates.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(coil_updates)
+    }
+}
+
+/// Switch off a pulsed coil, the write must not wait behind other commands
+async fn switch_off_pulsed(queue: &BusQueue, coil: &CoilState) -> StateResult<CoilUpdate> {
+    let result = queue
+        .write_coil(coil, false, Priority::Emergency)
+        .await
+        .map_err(StateError::from)
+        .and_then(|res| res);
+    if let Err(err) = &result {
+        warn!(name = %coil.name, %err, "could not switch off pulsed coil");
+    }
+    result
 }
 
 struct StateInner {
     params: Params,
-    config: Config,
-    modbus: Arc<TokioMutex<ModbusContext>>,
-    bus_state: Arc<BusState>,
+    config: RwLock<Arc<Config>>,
+    buses: BTreeMap<String, Arc<TokioMutex<Bus>>>,
+    bus_queues: BTreeMap<String, Arc<BusQueue>>,
+    bus_state: RwLock<Arc<BusState>>,
+    state_store: Option<StateStore>,
+    interlocks: Mutex<BTreeMap<String, Arc<Interlock>>>,
+    scheduler: Scheduler,
+    jobs: Jobs,
+}
+
+/// How a coil is switched
+#[derive(Debug, Clone, Copy)]
+enum Switch {
+    /// Switch the coil on or off, momentary coils are pulsed instead of switched on
+    Set(bool),
+    /// Switch the coil on for a duration and off again
+    Pulse(Option<Duration>),
+    /// Switch the coil to the opposite of its state
+    Toggle,
+    /// Write the status of the coil after its device reappeared, without persisting it
+    Restore(bool),
 }
 
-#[derive(Debug, thiserror::Error)]
+/// Errors of the state
+///
+/// The errors can be cloned, so the result of a coalesced bus command
+/// can be passed to all waiting callers.
+#[derive(Debug, Clone, thiserror::Error)]
 pub enum StateError {
     #[error("coil {0:?} not found")]
     CoilNotFound(String),
     #[error("tag {0:?} not found")]
     TagNotFound(String),
+    #[error("schedule {0:?} not found")]
+    ScheduleNotFound(String),
+    #[error("scene {0:?} not found")]
+    SceneNotFound(String),
+    #[error("job {0} not found")]
+    JobNotFound(u64),
+    #[error("bus {0:?} not found")]
+    BusNotFound(String),
+    #[error(transparent)]
+    Io(Arc<std::io::Error>),
     #[error(transparent)]
-    Io(#[from] std::io::Error),
+    Dorfbus(Arc<dorfbusext::DorfbusError>),
     #[error("got timeout on modbus")]
     Timeout,
+    #[error("device {0:?} is offline")]
+    DeviceOffline(String),
+    #[error("coil {coil:?} can not be switched on while coil {other:?} of interlock {group:?} is not off")]
+    Interlocked {
+        coil: String,
+        other: String,
+        group: String,
+    },
     #[error(transparent)]
-    OneshotRecvError(#[from] oneshot::error::RecvError),
+    OneshotRecvError(Arc<oneshot::error::RecvError>),
+}
+
+impl From<std::io::Error> for StateError {
+    fn from(err: std::io::Error) -> Self {
+        StateError::Io(Arc::new(err))
+    }
+}
+
+impl From<dorfbusext::DorfbusError> for StateError {
+    fn from(err: dorfbusext::DorfbusError) -> Self {
+        StateError::Dorfbus(Arc::new(err))
+    }
+}
+
+impl From<oneshot::error::RecvError> for StateError {
+    fn from(err: oneshot::error::RecvError) -> Self {
+        StateError::OneshotRecvError(Arc::new(err))
+    }
 }
 
 pub type StateResult<T> = Result<T, StateError>;
+
+#[cfg(test)]
+mod tests {
+    use super::StateError;
+    use crate::{
+        bus_queue::Priority,
+        bus_state::{CircuitBreakerState, CoilValue},
+        config::Config,
+        simulation::simulated_state,
+    };
+
+    #[tokio::test]
+    async fn staggered_switch_on_continues_after_failed_coil() {
+        let config: Config = toml::from_str(
+            r#"
+            switch-on-delay-ms = 10
+
+            [devices.card-a]
+            modbus-address = 1
+
+            [devices.card-b]
+            modbus-address = 2
+
+            [coils.light-1]
+            device = "card-a"
+            address = 0
+            default-status = "off"
+            tags = ["lights"]
+
+            [coils.light-2]
+            device = "card-b"
+            address = 0
+            default-status = "off"
+            tags = ["lights"]
+            "#,
+        )
+        .unwrap();
+        let state = simulated_state(config);
+        let bus_state = state.bus_state();
+        *bus_state.devices["card-a"].circuit_breaker.write().unwrap() = CircuitBreakerState::Open;
+
+        let result = state.set_tag("lights", true, Priority::Normal).await;
+        assert!(matches!(result, Err(StateError::DeviceOffline(_))));
+        assert!(matches!(
+            *bus_state.coils["light-2"].status.read().unwrap(),
+            CoilValue::On
+        ));
+    }
+    #[tokio::test]
+    async fn restored_coils_keep_interlock() {
+        let config: Config = toml::from_str(
+            r#"
+            [devices.card]
+            modbus-address = 1
+
+            [coils.screen-up]
+            device = "card"
+            address = 0
+            default-status = "on"
+
+            [coils.screen-down]
+            device = "card"
+            address = 1
+            default-status = "on"
+
+            [interlocks.screen]
+            coils = ["screen-up", "screen-down"]
+            "#,
+        )
+        .unwrap();
+        let state = simulated_state(config);
+        let bus_state = state.bus_state();
+        let up = bus_state.coils["screen-up"].as_ref();
+        let down = bus_state.coils["screen-down"].as_ref();
+        *up.status.write().unwrap() = CoilValue::Off;
+        *down.status.write().unwrap() = CoilValue::Off;
+
+        let results = state
+            .restore_coils(&[(up, true), (down, true)], Priority::Low)
+            .await;
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(StateError::Interlocked { .. })));
+        assert!(matches!(*down.status.read().unwrap(), CoilValue::Off));
+    }
+}
@@ -13,8 +13,16 @@ use pretty_assertions::assert_eq;
 
 use crate::{
     api::ApiErrorResponse,
-    bus_state::{BusState, CoilState, CoilUpdate, CoilValue, DeviceState},
-    config::{CoilConfig, Config, DeviceConfig, ResetCoilStatus},
+    bus_queue::{BusStatus, Priority},
+    bus_state::{BusState, CircuitBreakerState, CoilState, CoilUpdate, CoilValue, DeviceState},
+    config::{
+        BusConfig, CircuitBreakerConfig, CoilConfig, CoilMode, Config, DeviceConfig,
+        InterlockConfig, InterlockPolicy, ResetCoilStatus, RetryPolicy, RetryableError,
+        SceneConfig, ScheduleConfig, TagConfig,
+    },
+    jobs::{JobState, JobStatus},
+    scene::SceneStatus,
+    scheduler::ScheduleStatus,
 };
 
 fn cleanup_schemar(obj: &mut schemars::schema::SchemaObject) {
@@ -80,7 +88,7 @@ fn check_schemas() {
             cleanup_schemar(schema);
 
             let schema = serde_json::to_value(schema)
-                .expect(&format!("could not convert {} to json value", name));
+                .unwrap_or_else(|_| panic!("could not convert {} to json value", name));
             (name.clone(), schema)
         })
         .collect();
@@ -90,12 +98,59 @@ fn check_schemas() {
 
     let mut gen = schmea_gen();
 
-    let ignored_schemas = vec![];
+    let ignored_schemas: [&str; 0] = [];
 
     let derive_schemas: BTreeMap<String, Value> = vec![
         (BusState::schema_name(), BusState::json_schema(&mut gen)),
+        (BusStatus::schema_name(), BusStatus::json_schema(&mut gen)),
+        (Priority::schema_name(), Priority::json_schema(&mut gen)),
         (Config::schema_name(), Config::json_schema(&mut gen)),
+        (BusConfig::schema_name(), BusConfig::json_schema(&mut gen)),
         (CoilConfig::schema_name(), CoilConfig::json_schema(&mut gen)),
+        (CoilMode::schema_name(), CoilMode::json_schema(&mut gen)),
+        (
+            InterlockConfig::schema_name(),
+            InterlockConfig::json_schema(&mut gen),
+        ),
+        (
+            InterlockPolicy::schema_name(),
+            InterlockPolicy::json_schema(&mut gen),
+        ),
+        (
+            ScheduleConfig::schema_name(),
+            ScheduleConfig::json_schema(&mut gen),
+        ),
+        (
+            ScheduleStatus::schema_name(),
+            ScheduleStatus::json_schema(&mut gen),
+        ),
+        (TagConfig::schema_name(), TagConfig::json_schema(&mut gen)),
+        (
+            SceneConfig::schema_name(),
+            SceneConfig::json_schema(&mut gen),
+        ),
+        (
+            SceneStatus::schema_name(),
+            SceneStatus::json_schema(&mut gen),
+        ),
+        (JobState::schema_name(), JobState::json_schema(&mut gen)),
+        (JobStatus::schema_name(), JobStatus::json_schema(&mut gen)),
+        (
+            CircuitBreakerConfig::schema_name(),
+            CircuitBreakerConfig::json_schema(&mut gen),
+        ),
+        (
+            CircuitBreakerState::schema_name(),
+            CircuitBreakerState::json_schema(&mut gen),
+        ),
+        (
+            RetryPolicy::schema_name(),
+            RetryPolicy::json_schema(&mut gen),
+        ),
+        (
+            RetryableError::schema_name(),
+            RetryableError::json_schema(&mut gen),
+        ),
         (
             DeviceConfig::schema_name(),
             DeviceConfig::json_schema(&mut gen),
@@ -123,7 +178,7 @@ fn check_schemas() {
         }
 
         let schema = serde_json::to_value(schema)
-            .expect(&format!("could not convert {} to json value", name));
+            .unwrap_or_else(|_| panic!("could not convert {} to json value", name));
         (name.clone(), schema)
     })
     .collect();
@@ -0,0 +1,36 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+pub use dorfbusext::Transport;
+
+use crate::{
+    cli::Params,
+    config::{Config, DEFAULT_BUS},
+};
+
+/// Transports of all buses
+///
+/// These are the buses of the config or,
+/// if the config does not contain any bus, the `default` bus given on the command line.
+pub fn bus_transports(
+    params: &Params,
+    config: &Config,
+) -> anyhow::Result<BTreeMap<String, Transport>> {
+    if config.buses.is_empty() {
+        let transport = params
+            .transport
+            .clone()
+            .context("No bus is configured and no serial device was found")?;
+        return Ok([(DEFAULT_BUS.to_owned(), transport)].into_iter().collect());
+    }
+
+    config
+        .buses
+        .iter()
+        .map(|(name, bus)| {
+            let transport = Transport::parse(&bus.url, bus.boud)
+                .with_context(|| format!("Invalid URL of bus {}", name))?;
+            Ok((name.clone(), transport))
+        })
+        .collect()
+}
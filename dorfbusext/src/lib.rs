@@ -1,7 +1,53 @@
+use std::{
+    fmt::{self, Debug},
+    io,
+};
+
 use async_trait::async_trait;
 use thiserror::Error;
-use tokio_modbus::client::{Context as ModbusContext, Reader, Writer};
+use tokio_modbus::{
+    client::{Context as ModbusContext, Reader, Writer},
+    slave::{Slave, SlaveContext},
+};
+
+mod transport;
+
+pub use transport::Transport;
+
+/// Holding register containing the hardware version of a relais card
+pub const HARDWARE_VERSION_REGISTER: u16 = 0x20;
+
+/// Holding register containing the device address of a relais card
+pub const DEVICE_ADDRESS_REGISTER: u16 = 0x4000;
 
+/// A bus with relais cards
+///
+/// This abstracts the transport of the bus,
+/// so it can be replaced by a mock or wrapped, e.g. for instrumentation.
+#[async_trait]
+pub trait DorfbusBus: Send + Debug {
+    /// Select the device for all following requests.
+    fn select_slave(&mut self, slave: Slave);
+
+    /// Read the states of `count` consecutive coils, starting at coil `addr`.
+    async fn read_coils(&mut self, addr: u16, count: u16) -> DorfbusResult<Vec<bool>>;
+
+    /// Write the state of a single coil.
+    async fn write_coil(&mut self, addr: u16, value: bool) -> DorfbusResult<()>;
+
+    /// Write the states of consecutive coils, starting at coil `addr`.
+    async fn write_coils(&mut self, addr: u16, values: &[bool]) -> DorfbusResult<()>;
+
+    /// Read `count` consecutive holding registers, starting at register `addr`.
+    async fn read_registers(&mut self, addr: u16, count: u16) -> DorfbusResult<Vec<u16>>;
+
+    /// Write a single holding register.
+    async fn write_register(&mut self, addr: u16, value: u16) -> DorfbusResult<()>;
+}
+
+/// Commands of the relais cards
+///
+/// This is implemented for every [`DorfbusBus`].
 #[async_trait]
 pub trait DorfbusExt {
     /// Read the hardware version of a relais card.
@@ -9,6 +55,12 @@ pub trait DorfbusExt {
     /// Use `set_slave` to select a device.
     async fn read_hardware_version(&mut self) -> DorfbusResult<u16>;
 
+    /// Read the states of `count` consecutive coils of a relais card,
+    /// starting at coil `addr`.
+    ///
+    /// Use `set_slave` to select a device.
+    async fn read_coil_states(&mut self, addr: u16, count: u16) -> DorfbusResult<Vec<bool>>;
+
     /// Set the device address of a relais card.
     ///
     /// This will send a broadcast command.
@@ -17,10 +69,10 @@ pub trait DorfbusExt {
 }
 
 #[async_trait]
-impl DorfbusExt for ModbusContext {
+impl<T: DorfbusBus + ?Sized> DorfbusExt for T {
     async fn read_hardware_version(&mut self) -> DorfbusResult<u16> {
         let hardware_version = self
-            .read_holding_registers(0x20, 1)
+            .read_registers(HARDWARE_VERSION_REGISTER, 1)
             .await?
             .into_iter()
             .next()
@@ -28,21 +80,229 @@ impl DorfbusExt for ModbusContext {
         Ok(hardware_version)
     }
 
+    async fn read_coil_states(&mut self, addr: u16, count: u16) -> DorfbusResult<Vec<bool>> {
+        let coils = self.read_coils(addr, count).await?;
+        if coils.len() < count as usize {
+            return Err(DorfbusError::ModbusShortResponse);
+        }
+        Ok(coils)
+    }
+
     async fn set_device_address(&mut self, addr: u8) -> DorfbusResult<()> {
-        match self.write_single_register(0x4000, addr as u16).await {
+        match self
+            .write_register(DEVICE_ADDRESS_REGISTER, addr as u16)
+            .await
+        {
             Ok(()) => Ok(()),
-            Err(err) if err.kind() == std::io::ErrorKind::InvalidData => Ok(()),
-            Err(err) => Err(err.into()),
+            Err(DorfbusError::Io(err)) if err.kind() == io::ErrorKind::InvalidData => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[async_trait]
+impl DorfbusBus for ModbusContext {
+    fn select_slave(&mut self, slave: Slave) {
+        SlaveContext::set_slave(self, slave);
+    }
+
+    async fn read_coils(&mut self, addr: u16, count: u16) -> DorfbusResult<Vec<bool>> {
+        Ok(Reader::read_coils(self, addr, count).await?)
+    }
+
+    async fn write_coil(&mut self, addr: u16, value: bool) -> DorfbusResult<()> {
+        Ok(Writer::write_single_coil(self, addr, value).await?)
+    }
+
+    async fn write_coils(&mut self, addr: u16, values: &[bool]) -> DorfbusResult<()> {
+        Ok(Writer::write_multiple_coils(self, addr, values).await?)
+    }
+
+    async fn read_registers(&mut self, addr: u16, count: u16) -> DorfbusResult<Vec<u16>> {
+        Ok(Reader::read_holding_registers(self, addr, count).await?)
+    }
+
+    async fn write_register(&mut self, addr: u16, value: u16) -> DorfbusResult<()> {
+        Ok(Writer::write_single_register(self, addr, value).await?)
+    }
+}
+
+/// Exception code of a Modbus exception response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    IllegalFunction = 0x01,
+    IllegalDataAddress = 0x02,
+    IllegalDataValue = 0x03,
+    ServerDeviceFailure = 0x04,
+    Acknowledge = 0x05,
+    ServerDeviceBusy = 0x06,
+    MemoryParityError = 0x08,
+    GatewayPathUnavailable = 0x0A,
+    GatewayTargetDevice = 0x0B,
+}
+
+impl Exception {
+    const ALL: [Exception; 9] = [
+        Exception::IllegalFunction,
+        Exception::IllegalDataAddress,
+        Exception::IllegalDataValue,
+        Exception::ServerDeviceFailure,
+        Exception::Acknowledge,
+        Exception::ServerDeviceBusy,
+        Exception::MemoryParityError,
+        Exception::GatewayPathUnavailable,
+        Exception::GatewayTargetDevice,
+    ];
+
+    fn description(self) -> &'static str {
+        match self {
+            Exception::IllegalFunction => "Illegal function",
+            Exception::IllegalDataAddress => "Illegal data address",
+            Exception::IllegalDataValue => "Illegal data value",
+            Exception::ServerDeviceFailure => "Server device failure",
+            Exception::Acknowledge => "Acknowledge",
+            Exception::ServerDeviceBusy => "Server device busy",
+            Exception::MemoryParityError => "Memory parity error",
+            Exception::GatewayPathUnavailable => "Gateway path unavailable",
+            Exception::GatewayTargetDevice => "Gateway target device failed to respond",
         }
     }
 }
 
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+/// A Modbus exception response of a device
+///
+/// This is the same as the `ExceptionResponse` of tokio-modbus,
+/// which can not be named because tokio-modbus 0.5 does not export it.
+/// Buses which are not based on tokio-modbus, e.g. a simulation, return this type instead.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("Modbus function {function}: {exception}")]
+pub struct ExceptionResponse {
+    pub function: u8,
+    pub exception: Exception,
+}
+
+impl ExceptionResponse {
+    /// The exception response wrapped by an error of a Modbus request, if any
+    pub fn from_io_error(err: &io::Error) -> Option<ExceptionResponse> {
+        let inner = err.get_ref()?;
+        if let Some(exception) = inner.downcast_ref::<ExceptionResponse>() {
+            return Some(*exception);
+        }
+
+        // the exception response of tokio-modbus can not be downcast, use its fields instead;
+        // this depends on its derived Debug format, e.g.
+        // `ExceptionResponse { function: 1, exception: IllegalDataAddress }` in tokio-modbus 0.5
+        let fields = format!("{:?}", inner);
+        let fields = fields
+            .strip_prefix("ExceptionResponse { function: ")?
+            .strip_suffix(" }")?;
+        let (function, exception) = fields.split_once(", exception: ")?;
+        Some(ExceptionResponse {
+            function: function.parse().ok()?,
+            exception: Exception::ALL
+                .into_iter()
+                .find(|code| format!("{:?}", code) == exception)?,
+        })
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum DorfbusError {
     #[error("Got an empyt response from device")]
     ModbusEmptyResponse,
+    #[error("Got less coil states than requested from device")]
+    ModbusShortResponse,
+    #[error(transparent)]
+    Exception(ExceptionResponse),
     #[error(transparent)]
-    Io(#[from] std::io::Error),
+    Io(std::io::Error),
+}
+
+impl From<io::Error> for DorfbusError {
+    fn from(err: io::Error) -> Self {
+        match ExceptionResponse::from_io_error(&err) {
+            Some(exception) => DorfbusError::Exception(exception),
+            None => DorfbusError::Io(err),
+        }
+    }
+}
+
+impl DorfbusError {
+    /// Whether the device answered with a Modbus exception
+    pub fn is_exception(&self) -> bool {
+        matches!(self, DorfbusError::Exception(_))
+    }
+
+    /// Whether the device answered that it does not support the requested function
+    pub fn is_illegal_function(&self) -> bool {
+        matches!(
+            self,
+            DorfbusError::Exception(ExceptionResponse {
+                exception: Exception::IllegalFunction,
+                ..
+            })
+        )
+    }
 }
 
 pub type DorfbusResult<T> = Result<T, DorfbusError>;
+
+#[cfg(test)]
+mod tests {
+    use std::{error, fmt, io};
+
+    use super::{Exception, ExceptionResponse};
+
+    /// The exception response of tokio-modbus, which is not exported
+    mod tokio_modbus_frame {
+        #[derive(Debug)]
+        pub enum Exception {
+            IllegalDataAddress = 0x02,
+        }
+
+        #[derive(Debug)]
+        pub struct ExceptionResponse {
+            pub function: u8,
+            pub exception: Exception,
+        }
+    }
+
+    impl fmt::Display for tokio_modbus_frame::ExceptionResponse {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "Modbus function {}: {:?}", self.function, self.exception)
+        }
+    }
+
+    impl error::Error for tokio_modbus_frame::ExceptionResponse {}
+
+    #[test]
+    fn exception_response_from_io_error() {
+        let err = io::Error::other(tokio_modbus_frame::ExceptionResponse {
+            function: 1,
+            exception: tokio_modbus_frame::Exception::IllegalDataAddress,
+        });
+        assert_eq!(
+            ExceptionResponse::from_io_error(&err),
+            Some(ExceptionResponse {
+                function: 1,
+                exception: Exception::IllegalDataAddress,
+            })
+        );
+
+        let exception = ExceptionResponse {
+            function: 5,
+            exception: Exception::ServerDeviceBusy,
+        };
+        let err = io::Error::other(exception);
+        assert_eq!(ExceptionResponse::from_io_error(&err), Some(exception));
+
+        let err = io::Error::other("ExceptionResponse");
+        assert_eq!(ExceptionResponse::from_io_error(&err), None);
+    }
+}
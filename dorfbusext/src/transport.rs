@@ -0,0 +1,156 @@
+use std::{fmt, net::SocketAddr};
+
+use anyhow::{bail, Context};
+use tokio::net::lookup_host;
+use tokio_modbus::client::{rtu, tcp};
+use tokio_serial::SerialStream;
+
+use crate::DorfbusBus;
+
+/// Default port of Modbus TCP
+const MODBUS_TCP_PORT: u16 = 502;
+
+/// The connection to a Modbus bus
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    /// Modbus RTU over a serial device, e.g. a USB to RS485 adapter
+    Rtu { path: String, boud: u32 },
+    /// Modbus TCP, e.g. a RS485 to Ethernet gateway
+    Tcp { host: String, port: u16 },
+}
+
+impl Transport {
+    /// Parse a bus URL
+    ///
+    /// Supported are `rtu://<serial path>` and `tcp://<host>[:<port>]`.
+    /// The boud rate is only used for Modbus RTU.
+    pub fn parse(url: &str, boud: u32) -> anyhow::Result<Transport> {
+        if let Some(path) = url.strip_prefix("rtu://") {
+            if path.is_empty() {
+                bail!("the bus URL {:?} does not contain a serial path", url);
+            }
+            Ok(Transport::Rtu {
+                path: path.to_owned(),
+                boud,
+            })
+        } else if let Some(addr) = url.strip_prefix("tcp://") {
+            let (host, port) = parse_host_port(addr)
+                .with_context(|| format!("invalid address in bus URL {:?}", url))?;
+            Ok(Transport::Tcp {
+                host: host.to_owned(),
+                port,
+            })
+        } else {
+            bail!(
+                "unsupported bus URL {:?}, expected rtu://<serial path> or tcp://<host>:<port>",
+                url
+            )
+        }
+    }
+
+    /// Connect to the bus
+    pub async fn connect(&self) -> anyhow::Result<Box<dyn DorfbusBus>> {
+        match self {
+            Transport::Rtu { path, boud } => {
+                let builder = tokio_serial::new(path, *boud);
+                let port =
+                    SerialStream::open(&builder).context("Could not open the serial device")?;
+                Ok(Box::new(rtu::connect(port).await?))
+            }
+            Transport::Tcp { host, port } => {
+                let addr: SocketAddr = lookup_host((host.as_str(), *port))
+                    .await
+                    .with_context(|| format!("Could not resolve {}", host))?
+                    .next()
+                    .with_context(|| format!("{} did not resolve to an address", host))?;
+                let modbus_ctx = tcp::connect(addr)
+                    .await
+                    .with_context(|| format!("Could not connect to {}", addr))?;
+                Ok(Box::new(modbus_ctx))
+            }
+        }
+    }
+}
+
+/// Split `host`, `host:port` or `[ipv6]:port` into host and port
+fn parse_host_port(addr: &str) -> anyhow::Result<(&str, u16)> {
+    let (host, port) = if let Some(rest) = addr.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').context("missing closing bracket")?;
+        match rest {
+            "" => (host, None),
+            rest => (
+                host,
+                Some(rest.strip_prefix(':').context("expected a port")?),
+            ),
+        }
+    } else {
+        match addr.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (addr, None),
+        }
+    };
+
+    if host.is_empty() {
+        bail!("missing host");
+    }
+    let port = match port {
+        Some(port) => port.parse().context("invalid port")?,
+        None => MODBUS_TCP_PORT,
+    };
+    Ok((host, port))
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Rtu { path, boud } => write!(f, "rtu://{} ({} boud)", path, boud),
+            Transport::Tcp { host, port } if host.contains(':') => {
+                write!(f, "tcp://[{}]:{}", host, port)
+            }
+            Transport::Tcp { host, port } => write!(f, "tcp://{}:{}", host, port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Transport;
+
+    #[test]
+    fn parse_rtu_url() {
+        assert_eq!(
+            Transport::parse("rtu:///dev/ttyUSB0", 9600).unwrap(),
+            Transport::Rtu {
+                path: "/dev/ttyUSB0".to_owned(),
+                boud: 9600
+            }
+        );
+        assert!(Transport::parse("rtu://", 9600).is_err());
+    }
+
+    #[test]
+    fn parse_tcp_url() {
+        assert_eq!(
+            Transport::parse("tcp://gateway.local:5020", 9600).unwrap(),
+            Transport::Tcp {
+                host: "gateway.local".to_owned(),
+                port: 5020
+            }
+        );
+        assert_eq!(
+            Transport::parse("tcp://192.0.2.1", 9600).unwrap(),
+            Transport::Tcp {
+                host: "192.0.2.1".to_owned(),
+                port: 502
+            }
+        );
+        assert_eq!(
+            Transport::parse("tcp://[2001:db8::1]:502", 9600).unwrap(),
+            Transport::Tcp {
+                host: "2001:db8::1".to_owned(),
+                port: 502
+            }
+        );
+        assert!(Transport::parse("udp://192.0.2.1", 9600).is_err());
+    }
+}